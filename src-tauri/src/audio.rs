@@ -0,0 +1,258 @@
+// Voice messages. The frontend records mono PCM, which is encoded to opus
+// here and sent as an Audio message. For playback it is decoded back into a
+// WAV the frontend can play like any other.
+//
+// The payload of an Audio message is a run of opus packets, each one
+// FRAME_MS of audio, prefixed with its length as a little endian u16.
+
+use std::io::Cursor;
+use audiopus::{Application, Bitrate, Channels, MutSignals, SampleRate};
+use audiopus::coder::{Encoder, Decoder};
+use audiopus::packet::Packet;
+use tauri::{State, async_runtime};
+
+use crate::message::{Message, MessageData, AudioData};
+use crate::utilities::{gen_rand_id, get_curr_time};
+use crate::AppState;
+
+// Longest clip anyone can send us, or that we will send
+pub const MAX_CLIP_SECS: u32 = 30;
+const FRAME_MS: u32 = 20;
+// Opus packets can hold up to 120ms, however we split them
+const MAX_PACKET_MS: u32 = 120;
+const MAX_PACKET_LEN: usize = 1275;
+// Plenty for speech, and keeps the longest clip around 90 KiB
+const BITRATE: i32 = 24_000;
+
+fn opus_sample_rate(sample_rate: u32) -> Result<SampleRate, String> {
+    i32::try_from(sample_rate).ok()
+        .and_then(|rate| SampleRate::try_from(rate).ok())
+        .ok_or_else(|| format!("Opus doesn't support a sample rate of {sample_rate}Hz"))
+}
+
+fn frame_len(sample_rate: u32, ms: u32) -> usize {
+    (sample_rate * ms / 1000) as usize
+}
+
+// Encodes a mono clip, padding the end out to a whole frame. Returns the
+// payload and how long the clip is.
+pub fn encode(pcm: &[f32], sample_rate: u32) -> Result<(Vec<u8>, u32), String> {
+    let max_samples = sample_rate as usize * MAX_CLIP_SECS as usize;
+    if pcm.len() > max_samples {
+        return Err(format!("Voice messages can be at most {MAX_CLIP_SECS}s long"));
+    }
+
+    let mut encoder = Encoder::new(opus_sample_rate(sample_rate)?, Channels::Mono, Application::Voip)
+        .map_err(|e| format!("Could not create opus encoder: {e}"))?;
+    encoder.set_bitrate(Bitrate::BitsPerSecond(BITRATE))
+        .map_err(|e| format!("Could not set opus bitrate: {e}"))?;
+
+    let frame_len = frame_len(sample_rate, FRAME_MS);
+    let mut payload = Vec::new();
+    let mut packet = [0u8; MAX_PACKET_LEN];
+    for frame in pcm.chunks(frame_len) {
+        let mut padded;
+        let frame = if frame.len() < frame_len {
+            padded = frame.to_vec();
+            padded.resize(frame_len, 0.0);
+            &padded[..]
+        } else {
+            frame
+        };
+
+        let len = encoder.encode_float(frame, &mut packet)
+            .map_err(|e| format!("Could not encode audio: {e}"))?;
+        payload.extend((len as u16).to_le_bytes());
+        payload.extend_from_slice(&packet[..len]);
+    }
+
+    let duration_ms = (pcm.len() as u64 * 1000 / sample_rate as u64) as u32;
+    Ok((payload, duration_ms))
+}
+
+fn split_packets(payload: &[u8]) -> Result<Vec<&[u8]>, String> {
+    let mut packets = Vec::new();
+    let mut rest = payload;
+    while !rest.is_empty() {
+        if rest.len() < 2 {
+            return Err("truncated packet length".to_owned());
+        }
+        let len = u16::from_le_bytes([rest[0], rest[1]]) as usize;
+        if len == 0 || len > MAX_PACKET_LEN || rest.len() - 2 < len {
+            return Err(format!("bad packet length {len}"));
+        }
+        packets.push(&rest[2..2 + len]);
+        rest = &rest[2 + len..];
+    }
+    Ok(packets)
+}
+
+// Clips come from peers, so they are checked before we show them, and before
+// we try to decode them
+pub fn check(audio: &AudioData) -> Result<(), String> {
+    opus_sample_rate(audio.sample_rate)?;
+    if audio.duration_ms > MAX_CLIP_SECS * 1000 {
+        return Err(format!("clip is {}ms long, more than the {MAX_CLIP_SECS}s allowed", audio.duration_ms));
+    }
+    let packets = split_packets(&audio.data.payload)?;
+    // every packet is at least 2.5ms, and our own are all FRAME_MS
+    if packets.len() as u32 > MAX_CLIP_SECS * 1000 * 2 / 5 {
+        return Err(format!("{} packets is more than a {MAX_CLIP_SECS}s clip could have", packets.len()));
+    }
+    Ok(())
+}
+
+// Decodes a clip back into 16 bit PCM, cutting it off at MAX_CLIP_SECS
+pub fn decode(audio: &AudioData) -> Result<Vec<i16>, String> {
+    check(audio)?;
+
+    let mut decoder = Decoder::new(opus_sample_rate(audio.sample_rate)?, Channels::Mono)
+        .map_err(|e| format!("Could not create opus decoder: {e}"))?;
+
+    let max_samples = audio.sample_rate as usize * MAX_CLIP_SECS as usize;
+    let mut pcm = Vec::new();
+    let mut frame = vec![0i16; frame_len(audio.sample_rate, MAX_PACKET_MS)];
+    for packet in split_packets(&audio.data.payload)? {
+        let packet = Packet::try_from(packet).map_err(|e| format!("Bad opus packet: {e}"))?;
+        let output = MutSignals::try_from(&mut frame[..]).map_err(|e| e.to_string())?;
+        let len = decoder.decode(Some(packet), output, false)
+            .map_err(|e| format!("Could not decode audio: {e}"))?;
+        pcm.extend_from_slice(&frame[..len]);
+        if pcm.len() >= max_samples {
+            break;
+        }
+    }
+
+    // Drop the padding on the last frame
+    let len = frame_len(audio.sample_rate, audio.duration_ms.min(MAX_CLIP_SECS * 1000));
+    pcm.truncate(len);
+    Ok(pcm)
+}
+
+pub fn to_wav(pcm: &[i16], sample_rate: u32) -> Result<Vec<u8>, String> {
+    let spec = hound::WavSpec {
+        channels: 1,
+        sample_rate,
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+    };
+
+    let mut wav = Cursor::new(Vec::new());
+    let mut writer = hound::WavWriter::new(&mut wav, spec).map_err(|e| e.to_string())?;
+    for sample in pcm {
+        writer.write_sample(*sample).map_err(|e| e.to_string())?;
+    }
+    writer.finalize().map_err(|e| e.to_string())?;
+    Ok(wav.into_inner())
+}
+
+// pcm is mono, as recorded by the frontend, at one of the sample rates opus
+// supports (8, 12, 16, 24 or 48kHz)
+#[tauri::command]
+pub async fn cmd_send_audio(pcm: Vec<f32>, sample_rate: u32, state: State<'_, AppState>) -> Result<(), String> {
+    let (payload, duration_ms) = async_runtime::spawn_blocking(move || encode(&pcm, sample_rate))
+        .await
+        .map_err(|e| e.to_string())??;
+
+    let (name, uid) = {
+        let profile = state.profile.lock().unwrap();
+        (profile.name.clone(), profile.uid)
+    };
+
+    let msg = state.identity.lock().unwrap().sign(Message::Audio(AudioData {
+        data: MessageData::new(name, uid, gen_rand_id(), get_curr_time(), payload),
+        duration_ms,
+        sample_rate,
+    }));
+
+    state.connection.send(vec![msg]).await
+}
+
+// Returns the voice message with id mid as a WAV file, ready to play
+#[tauri::command]
+pub async fn cmd_decode_audio(mid: u32, state: State<'_, AppState>) -> Result<Vec<u8>, String> {
+    let audio = state.msg_history.lock().unwrap().iter()
+        .find_map(|msg| match msg {
+            Message::Audio(audio) if audio.data.mid == mid => Some(audio.clone()),
+            _ => None,
+        })
+        .ok_or_else(|| format!("No voice message with id {mid}"))?;
+
+    async_runtime::spawn_blocking(move || to_wav(&decode(&audio)?, audio.sample_rate))
+        .await
+        .map_err(|e| e.to_string())?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{MessageLimits, WireFormat};
+
+    // Packets of len bytes, each FRAME_MS long, for secs seconds
+    fn clip(secs: u32, len: usize) -> AudioData {
+        let mut payload = Vec::new();
+        for _ in 0..secs * 1000 / FRAME_MS {
+            payload.extend((len as u16).to_le_bytes());
+            payload.extend(vec![255; len]);
+        }
+        AudioData {
+            data: MessageData::new("someone".to_owned(), 7, 42, 1_700_000_000, payload),
+            duration_ms: secs * 1000,
+            sample_rate: 48000,
+        }
+    }
+
+    #[test]
+    fn accepts_the_longest_clip() {
+        assert_eq!(check(&clip(MAX_CLIP_SECS, 60)), Ok(()));
+    }
+
+    #[test]
+    fn rejects_long_clips() {
+        assert!(check(&clip(MAX_CLIP_SECS + 1, 60)).is_err());
+
+        let mut audio = clip(1, 60);
+        audio.duration_ms = MAX_CLIP_SECS * 1000 + 1;
+        assert!(check(&audio).is_err());
+    }
+
+    #[test]
+    fn rejects_too_many_packets() {
+        // 2.5ms packets are the shortest there are
+        let mut audio = clip(MAX_CLIP_SECS, 1);
+        audio.data.payload = [1u16.to_le_bytes().to_vec(), vec![0]].concat().repeat(MAX_CLIP_SECS as usize * 400 + 1);
+        audio.duration_ms = 1000;
+        assert!(check(&audio).is_err());
+    }
+
+    #[test]
+    fn rejects_bad_packets() {
+        let mut audio = clip(1, 60);
+        audio.data.payload.pop();
+        assert!(check(&audio).is_err());
+
+        audio.data.payload = vec![0, 0];
+        assert!(check(&audio).is_err());
+
+        audio.data.payload = ((MAX_PACKET_LEN + 1) as u16).to_le_bytes().to_vec();
+        assert!(check(&audio).is_err());
+    }
+
+    #[test]
+    fn rejects_unsupported_sample_rates() {
+        let mut audio = clip(1, 60);
+        audio.sample_rate = 44100;
+        assert!(check(&audio).is_err());
+    }
+
+    #[test]
+    fn longest_clip_fits_the_limits() {
+        // At our bitrate, with every byte as long as it gets as json
+        let len = (BITRATE as u32 / 8 * FRAME_MS / 1000) as usize;
+        let msg = Message::Audio(clip(MAX_CLIP_SECS, len));
+        for format in [WireFormat::Json, WireFormat::Binary] {
+            let frame = msg.to_network(format);
+            assert!(Message::from_network(&frame, &MessageLimits::default()).is_ok(), "too big as {format:?}");
+        }
+    }
+}
@@ -0,0 +1,523 @@
+// Compact binary encoding for Message, used instead of gzipped json once both
+// sides of a connection have said they support it in their Hello.
+//
+// Frame body layout (after the 8 byte length header):
+//   [MAGIC][BINARY_VERSION][flags][body...]
+// where body is gzipped if FLAG_GZIP is set. The magic byte can never be the
+// start of a legacy frame, since those always start with the gzip magic 0x1f.
+//
+// Inside the body, every message starts with a one byte tag followed by its
+// fields. Integers are little endian, and strings and byte arrays are
+// prefixed with their length as a u32.
+
+use std::io::Write;
+use flate2::Compression;
+use flate2::write::GzEncoder;
+
+use crate::message::{Message, MessageData, HelloData, BroadcastData, ChunkData, FileData, AudioData, DirectData, GroupData, GroupMsgData, DecodeError, inflate};
+
+pub const MAGIC: u8 = 0xEC;
+pub const BINARY_VERSION: u8 = 1;
+
+const FLAG_GZIP: u8 = 0b0000_0001;
+const PREAMBLE_LEN: usize = 3; // magic, version, flags
+
+// Anything smaller than this isn't worth the gzip header
+const MIN_COMPRESS_LEN: usize = 256;
+
+const TAG_BROADCAST: u8 = 0;
+const TAG_HELLO: u8 = 1;
+const TAG_GOODBYE: u8 = 2;
+const TAG_DROPPED: u8 = 3;
+const TAG_TEXT: u8 = 4;
+const TAG_IMAGE: u8 = 5;
+const TAG_ACK: u8 = 6;
+const TAG_PING: u8 = 7;
+const TAG_PONG: u8 = 8;
+const TAG_SYNC: u8 = 9;
+const TAG_CHUNK: u8 = 10;
+const TAG_FILE: u8 = 11;
+const TAG_FILE_REQUEST: u8 = 12;
+const TAG_FILE_PART: u8 = 13;
+const TAG_AUDIO: u8 = 14;
+const TAG_DIRECT_TEXT: u8 = 15;
+const TAG_DIRECT_IMAGE: u8 = 16;
+const TAG_GROUP_CREATE: u8 = 17;
+const TAG_GROUP_UPDATE: u8 = 18;
+const TAG_GROUP_TEXT: u8 = 19;
+const TAG_GROUP_IMAGE: u8 = 20;
+const TAG_DISCONNECT: u8 = 21;
+const TAG_ROOM_SHARE: u8 = 22;
+
+pub fn encode(msg: &Message) -> Vec<u8> {
+    let mut body = Vec::new();
+    write_msg(&mut body, msg);
+
+    let mut flags = 0;
+    if body.len() >= MIN_COMPRESS_LEN {
+        let mut e = GzEncoder::new(Vec::new(), Compression::default());
+        let compressed = e.write_all(&body).and_then(|_| e.finish());
+        match compressed {
+            Ok(compressed) if compressed.len() < body.len() => {
+                body = compressed;
+                flags |= FLAG_GZIP;
+            },
+            Ok(_) => {}, // e.g. noisy image data, just send it raw
+            Err(err) => log::error!("{err}"),
+        }
+    }
+
+    [vec![MAGIC, BINARY_VERSION, flags], body].concat()
+}
+
+// buf is the frame body, starting with the magic byte. Returns the message
+// along with its decompressed size so the caller can check it against limits.
+pub fn decode(buf: &[u8], max_decompressed: u64) -> Result<(Message, u64), DecodeError> {
+    if buf.len() < PREAMBLE_LEN {
+        return Err(DecodeError::TruncatedHeader { len: buf.len() });
+    }
+    let (version, flags) = (buf[1], buf[2]);
+    if version > BINARY_VERSION {
+        return Err(DecodeError::UnsupportedVersion(version));
+    }
+
+    let body = &buf[PREAMBLE_LEN..];
+    let inflated;
+    let body = if flags & FLAG_GZIP != 0 {
+        inflated = inflate(body, max_decompressed)?;
+        &inflated[..]
+    } else {
+        if body.len() as u64 > max_decompressed {
+            return Err(DecodeError::DecompressedTooLarge { max: max_decompressed });
+        }
+        body
+    };
+
+    let mut reader = Reader { buf: body, pos: 0 };
+    let msg = reader.read_msg()?;
+    if reader.pos != body.len() {
+        return Err(DecodeError::SchemaMismatch(
+            format!("{} trailing bytes after {} message", body.len() - reader.pos, msg.get_type_str())
+        ));
+    }
+
+    Ok((msg, body.len() as u64))
+}
+
+fn write_msg(out: &mut Vec<u8>, msg: &Message) {
+    match msg {
+        Message::Broadcast(data) => {
+            out.push(TAG_BROADCAST);
+            out.extend(data.uid.to_le_bytes());
+            out.extend(data.port.to_le_bytes());
+            out.extend(data.protocol_version.to_le_bytes());
+            write_bytes(out, data.room.as_bytes());
+            out.extend(data.members.to_le_bytes());
+            out.push(data.locked as u8);
+        },
+        Message::Hello(hello) => {
+            out.push(TAG_HELLO);
+            write_data(out, &hello.data);
+            out.extend(hello.protocol_version.to_le_bytes());
+            out.extend((hello.capabilities.len() as u32).to_le_bytes());
+            for capability in &hello.capabilities {
+                write_bytes(out, capability.as_bytes());
+            }
+            out.extend(hello.listen_port.to_le_bytes());
+            write_bytes(out, hello.room.as_bytes());
+            write_bytes(out, &hello.public_key);
+            write_bytes(out, &hello.handshake_sig);
+            write_bytes(out, &hello.room_proof);
+        },
+        Message::Goodbye(data) => {
+            out.push(TAG_GOODBYE);
+            write_data(out, data);
+        },
+        Message::Dropped(data) => {
+            out.push(TAG_DROPPED);
+            write_data(out, data);
+        },
+        Message::Text(data) => {
+            out.push(TAG_TEXT);
+            write_data(out, data);
+        },
+        Message::Image(data) => {
+            out.push(TAG_IMAGE);
+            write_data(out, data);
+        },
+        Message::Ack { uid, mid } => {
+            out.push(TAG_ACK);
+            out.extend(uid.to_le_bytes());
+            out.extend(mid.to_le_bytes());
+        },
+        Message::Ping(nonce) => {
+            out.push(TAG_PING);
+            out.extend(nonce.to_le_bytes());
+        },
+        Message::Pong(nonce) => {
+            out.push(TAG_PONG);
+            out.extend(nonce.to_le_bytes());
+        },
+        Message::Sync { seen } => {
+            out.push(TAG_SYNC);
+            out.extend((seen.len() as u32).to_le_bytes());
+            for mid in seen {
+                out.extend(mid.to_le_bytes());
+            }
+        },
+        Message::Chunk(chunk) => {
+            out.push(TAG_CHUNK);
+            out.extend(chunk.transfer_id.to_le_bytes());
+            out.extend(chunk.seq.to_le_bytes());
+            out.extend(chunk.total_len.to_le_bytes());
+            out.push(chunk.last as u8);
+            write_bytes(out, &chunk.hash);
+            write_bytes(out, &chunk.payload);
+        },
+        Message::File(file) => {
+            out.push(TAG_FILE);
+            write_data(out, &file.data);
+            write_bytes(out, file.file_name.as_bytes());
+            out.extend(file.size.to_le_bytes());
+            write_bytes(out, file.mime.as_bytes());
+            write_bytes(out, &file.hash);
+        },
+        Message::FileRequest { mid, offset } => {
+            out.push(TAG_FILE_REQUEST);
+            out.extend(mid.to_le_bytes());
+            out.extend(offset.to_le_bytes());
+        },
+        Message::FilePart { mid, offset, data } => {
+            out.push(TAG_FILE_PART);
+            out.extend(mid.to_le_bytes());
+            out.extend(offset.to_le_bytes());
+            write_bytes(out, data);
+        },
+        Message::Audio(audio) => {
+            out.push(TAG_AUDIO);
+            write_data(out, &audio.data);
+            out.extend(audio.duration_ms.to_le_bytes());
+            out.extend(audio.sample_rate.to_le_bytes());
+        },
+        Message::DirectText(direct) => {
+            out.push(TAG_DIRECT_TEXT);
+            write_direct(out, direct);
+        },
+        Message::DirectImage(direct) => {
+            out.push(TAG_DIRECT_IMAGE);
+            write_direct(out, direct);
+        },
+        Message::GroupCreate(group) => {
+            out.push(TAG_GROUP_CREATE);
+            write_group(out, group);
+        },
+        Message::GroupUpdate(group) => {
+            out.push(TAG_GROUP_UPDATE);
+            write_group(out, group);
+        },
+        Message::GroupText(group_msg) => {
+            out.push(TAG_GROUP_TEXT);
+            write_data(out, &group_msg.data);
+            out.extend(group_msg.gid.to_le_bytes());
+        },
+        Message::GroupImage(group_msg) => {
+            out.push(TAG_GROUP_IMAGE);
+            write_data(out, &group_msg.data);
+            out.extend(group_msg.gid.to_le_bytes());
+        },
+        Message::Disconnect { reason } => {
+            out.push(TAG_DISCONNECT);
+            write_bytes(out, reason.as_bytes());
+        },
+        Message::RoomShare(share) => {
+            out.push(TAG_ROOM_SHARE);
+            write_bytes(out, share);
+        },
+    }
+}
+
+fn write_data(out: &mut Vec<u8>, data: &MessageData) {
+    write_bytes(out, data.name.as_bytes());
+    out.extend(data.uid.to_le_bytes());
+    out.extend(data.mid.to_le_bytes());
+    out.extend(data.timestamp.to_le_bytes());
+    write_bytes(out, &data.payload);
+    write_bytes(out, &data.signature);
+}
+
+fn write_direct(out: &mut Vec<u8>, direct: &DirectData) {
+    write_data(out, &direct.data);
+    out.extend(direct.to.to_le_bytes());
+}
+
+fn write_group(out: &mut Vec<u8>, group: &GroupData) {
+    write_data(out, &group.data);
+    out.extend(group.gid.to_le_bytes());
+    write_bytes(out, group.group_name.as_bytes());
+    out.extend((group.members.len() as u32).to_le_bytes());
+    for uid in &group.members {
+        out.extend(uid.to_le_bytes());
+    }
+}
+
+fn write_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend((bytes.len() as u32).to_le_bytes());
+    out.extend_from_slice(bytes);
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], DecodeError> {
+        let remaining = self.buf.len() - self.pos;
+        if len > remaining {
+            return Err(DecodeError::SchemaMismatch(
+                format!("field of {len} bytes at offset {} runs past end of message", self.pos)
+            ));
+        }
+        let bytes = &self.buf[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn read_array<const N: usize>(&mut self) -> Result<[u8; N], DecodeError> {
+        let mut arr = [0u8; N];
+        arr.copy_from_slice(self.take(N)?);
+        Ok(arr)
+    }
+
+    fn read_u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.read_array::<1>()?[0])
+    }
+
+    fn read_bool(&mut self) -> Result<bool, DecodeError> {
+        match self.read_u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            b => Err(DecodeError::SchemaMismatch(format!("{b} is not a bool at offset {}", self.pos - 1))),
+        }
+    }
+
+    fn read_u16(&mut self) -> Result<u16, DecodeError> {
+        Ok(u16::from_le_bytes(self.read_array()?))
+    }
+
+    fn read_u32(&mut self) -> Result<u32, DecodeError> {
+        Ok(u32::from_le_bytes(self.read_array()?))
+    }
+
+    fn read_u64(&mut self) -> Result<u64, DecodeError> {
+        Ok(u64::from_le_bytes(self.read_array()?))
+    }
+
+    fn read_bytes(&mut self) -> Result<Vec<u8>, DecodeError> {
+        let len = self.read_u32()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    fn read_string(&mut self) -> Result<String, DecodeError> {
+        String::from_utf8(self.read_bytes()?).map_err(DecodeError::InvalidUtf8)
+    }
+
+    fn read_data(&mut self) -> Result<MessageData, DecodeError> {
+        Ok(MessageData {
+            name: self.read_string()?,
+            uid: self.read_u32()?,
+            mid: self.read_u32()?,
+            timestamp: self.read_u64()?,
+            payload: self.read_bytes()?,
+            signature: self.read_bytes()?,
+            verified: false,
+        })
+    }
+
+    fn read_direct(&mut self) -> Result<DirectData, DecodeError> {
+        Ok(DirectData {
+            data: self.read_data()?,
+            to: self.read_u32()?,
+        })
+    }
+
+    fn read_group(&mut self) -> Result<GroupData, DecodeError> {
+        let data = self.read_data()?;
+        let gid = self.read_u32()?;
+        let group_name = self.read_string()?;
+        let num_members = self.read_u32()?;
+        let members = (0..num_members)
+            .map(|_| self.read_u32())
+            .collect::<Result<Vec<u32>, DecodeError>>()?;
+        Ok(GroupData { data, gid, group_name, members })
+    }
+
+    fn read_group_msg(&mut self) -> Result<GroupMsgData, DecodeError> {
+        Ok(GroupMsgData {
+            data: self.read_data()?,
+            gid: self.read_u32()?,
+        })
+    }
+
+    fn read_msg(&mut self) -> Result<Message, DecodeError> {
+        let msg = match self.read_u8()? {
+            TAG_BROADCAST => Message::Broadcast(BroadcastData {
+                uid: self.read_u32()?,
+                port: self.read_u16()?,
+                protocol_version: self.read_u16()?,
+                room: self.read_string()?,
+                members: self.read_u32()?,
+                locked: self.read_bool()?,
+            }),
+            TAG_HELLO => {
+                let data = self.read_data()?;
+                let protocol_version = self.read_u16()?;
+                let num_capabilities = self.read_u32()?;
+                let capabilities = (0..num_capabilities)
+                    .map(|_| self.read_string())
+                    .collect::<Result<Vec<String>, DecodeError>>()?;
+                let listen_port = self.read_u16()?;
+                let room = self.read_string()?;
+                let public_key = self.read_bytes()?;
+                let handshake_sig = self.read_bytes()?;
+                let room_proof = self.read_bytes()?;
+                Message::Hello(Box::new(HelloData {
+                    data, protocol_version, capabilities, listen_port, room, public_key, handshake_sig, room_proof
+                }))
+            },
+            TAG_GOODBYE => Message::Goodbye(self.read_data()?),
+            TAG_DROPPED => Message::Dropped(self.read_data()?),
+            TAG_TEXT => Message::Text(self.read_data()?),
+            TAG_IMAGE => Message::Image(self.read_data()?),
+            TAG_ACK => Message::Ack { uid: self.read_u32()?, mid: self.read_u32()? },
+            TAG_PING => Message::Ping(self.read_u32()?),
+            TAG_PONG => Message::Pong(self.read_u32()?),
+            TAG_SYNC => {
+                let num_seen = self.read_u32()?;
+                let seen = (0..num_seen)
+                    .map(|_| self.read_u32())
+                    .collect::<Result<Vec<u32>, DecodeError>>()?;
+                Message::Sync { seen }
+            },
+            TAG_CHUNK => Message::Chunk(ChunkData {
+                transfer_id: self.read_u32()?,
+                seq: self.read_u32()?,
+                total_len: self.read_u64()?,
+                last: self.read_bool()?,
+                hash: self.read_bytes()?,
+                payload: self.read_bytes()?,
+            }),
+            TAG_FILE => Message::File(Box::new(FileData {
+                data: self.read_data()?,
+                file_name: self.read_string()?,
+                size: self.read_u64()?,
+                mime: self.read_string()?,
+                hash: self.read_bytes()?,
+            })),
+            TAG_FILE_REQUEST => Message::FileRequest { mid: self.read_u32()?, offset: self.read_u64()? },
+            TAG_FILE_PART => Message::FilePart {
+                mid: self.read_u32()?,
+                offset: self.read_u64()?,
+                data: self.read_bytes()?,
+            },
+            TAG_AUDIO => Message::Audio(AudioData {
+                data: self.read_data()?,
+                duration_ms: self.read_u32()?,
+                sample_rate: self.read_u32()?,
+            }),
+            TAG_DIRECT_TEXT => Message::DirectText(self.read_direct()?),
+            TAG_DIRECT_IMAGE => Message::DirectImage(self.read_direct()?),
+            TAG_GROUP_CREATE => Message::GroupCreate(Box::new(self.read_group()?)),
+            TAG_GROUP_UPDATE => Message::GroupUpdate(Box::new(self.read_group()?)),
+            TAG_GROUP_TEXT => Message::GroupText(self.read_group_msg()?),
+            TAG_GROUP_IMAGE => Message::GroupImage(self.read_group_msg()?),
+            TAG_DISCONNECT => Message::Disconnect { reason: self.read_string()? },
+            TAG_ROOM_SHARE => Message::RoomShare(self.read_bytes()?),
+            tag => return Err(DecodeError::UnknownVariant(format!("tag {tag}"))),
+        };
+        Ok(msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data(payload: Vec<u8>) -> MessageData {
+        let mut data = MessageData::new("someone".to_owned(), 7, 42, 1_700_000_000, payload);
+        data.signature = vec![9; 64];
+        data
+    }
+
+    fn every_message() -> Vec<Message> {
+        vec![
+            Message::Broadcast(BroadcastData::new(7, 61000, "C".to_owned(), 3, true)),
+            Message::Hello(Box::new(HelloData::new(
+                data(vec![1, 2, 3]), 61000, "B".to_owned(), vec![4; 32], vec![5; 64], vec![6; 32],
+            ))),
+            Message::Goodbye(data(Vec::new())),
+            Message::Dropped(data(Vec::new())),
+            Message::Text(data(b"hi".to_vec())),
+            // big and repetitive, so it gets gzipped
+            Message::Image(data(vec![0; 4096])),
+            Message::Ack { uid: 7, mid: 42 },
+            Message::Ping(1),
+            Message::Pong(2),
+            Message::Sync { seen: vec![1, 2, 3] },
+            Message::Chunk(ChunkData {
+                transfer_id: 1, seq: 2, total_len: 3, last: true, hash: vec![8; 32], payload: vec![1; 16],
+            }),
+            Message::File(Box::new(FileData {
+                data: data(Vec::new()),
+                file_name: "notes.txt".to_owned(),
+                size: 1234,
+                mime: "text/plain".to_owned(),
+                hash: vec![8; 32],
+            })),
+            Message::FileRequest { mid: 42, offset: 100 },
+            Message::FilePart { mid: 42, offset: 100, data: vec![1, 2, 3] },
+            Message::Audio(AudioData { data: data(vec![1; 10]), duration_ms: 1500, sample_rate: 48000 }),
+            Message::DirectText(DirectData { data: data(b"psst".to_vec()), to: 8 }),
+            Message::DirectImage(DirectData { data: data(vec![2; 10]), to: 8 }),
+            Message::GroupCreate(Box::new(GroupData {
+                data: data(Vec::new()), gid: 5, group_name: "friends".to_owned(), members: vec![7, 8, 9],
+            })),
+            Message::GroupUpdate(Box::new(GroupData {
+                data: data(Vec::new()), gid: 5, group_name: "friends".to_owned(), members: vec![7, 8],
+            })),
+            Message::GroupText(GroupMsgData { data: data(b"hey all".to_vec()), gid: 5 }),
+            Message::GroupImage(GroupMsgData { data: data(vec![3; 10]), gid: 5 }),
+            Message::Disconnect { reason: "Room B is full".to_owned() },
+            Message::RoomShare(vec![7; 32]),
+        ]
+    }
+
+    #[test]
+    fn round_trip() {
+        for msg in every_message() {
+            let (decoded, _) = decode(&encode(&msg), u64::MAX).unwrap();
+            assert_eq!(decoded, msg);
+        }
+    }
+
+    #[test]
+    fn rejects_bad_bool() {
+        let mut buf = encode(&Message::Broadcast(BroadcastData::new(7, 61000, "C".to_owned(), 3, true)));
+        *buf.last_mut().unwrap() = 2;
+        assert!(matches!(decode(&buf, u64::MAX), Err(DecodeError::SchemaMismatch(_))));
+    }
+
+    #[test]
+    fn rejects_newer_versions() {
+        let mut buf = encode(&Message::Ping(1));
+        buf[1] = BINARY_VERSION + 1;
+        assert!(matches!(decode(&buf, u64::MAX), Err(DecodeError::UnsupportedVersion(v)) if v == BINARY_VERSION + 1));
+    }
+
+    #[test]
+    fn rejects_trailing_bytes() {
+        let mut buf = encode(&Message::Ping(1));
+        buf.push(0);
+        assert!(matches!(decode(&buf, u64::MAX), Err(DecodeError::SchemaMismatch(_))));
+    }
+}
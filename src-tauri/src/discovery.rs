@@ -0,0 +1,288 @@
+use std::{net::{UdpSocket, SocketAddr, SocketAddrV6, Ipv4Addr, Ipv6Addr}, sync::Mutex, collections::HashSet};
+use serde::{Serialize, Deserialize};
+use socket2::{Socket, Domain, Type, Protocol, SockRef};
+use ts_rs::TS;
+use tauri::State;
+
+use crate::interfaces;
+use crate::AppState;
+
+// Every discovery transport uses the same port, just different destinations
+pub const DISCOVERY_PORT: u16 = 59813;
+// Administratively scoped, so it never leaves the site
+const MULTICAST_V4_GROUP: Ipv4Addr = Ipv4Addr::new(239, 255, 236, 112);
+// Link-local scope, only ever reaches the local segment
+const MULTICAST_V6_GROUP: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0xec70, 0x6368);
+
+// Which transports we use to announce ourselves and find others. Some
+// networks filter broadcast but allow multicast, and some are IPv6 only.
+#[derive(TS, Serialize, Deserialize, Clone, Debug)]
+#[ts(export)]
+#[ts(export_to="../src/lib/bindings/")]
+pub struct DiscoveryConfig {
+    pub broadcast: bool,
+    pub multicast_v4: bool,
+    pub multicast_v6: bool,
+    pub mdns: bool,
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        DiscoveryConfig { broadcast: true, multicast_v4: false, multicast_v6: false, mdns: true }
+    }
+}
+
+pub struct Discovery {
+    socket_v4: Option<UdpSocket>, // None if the port couldn't be bound
+    socket_v6: Option<UdpSocket>, // None if this machine has no IPv6
+    config: Mutex<DiscoveryConfig>,
+    // Whether we could join the IPv4 multicast group on loopback, which is
+    // how we reach other instances on this machine
+    loopback_multicast: bool,
+
+    // Interfaces (by address for v4 and index for v6) that we have joined
+    // the multicast group on. Interfaces can come and go while we are
+    // running, so this is checked every time we send.
+    joined_v4: Mutex<HashSet<Ipv4Addr>>,
+    joined_v6: Mutex<HashSet<u32>>,
+}
+
+impl Discovery {
+    // Without an IPv4 socket the only way to find anyone is mDNS and static
+    // peers, but that is better than not starting at all
+    pub fn new() -> Discovery {
+        let socket_v4 = match bind_v4_socket() {
+            Ok(socket) => Some(socket),
+            Err(e) => {
+                log::error!("Could not create IPv4 discovery socket on port {DISCOVERY_PORT}, IPv4 discovery disabled: {e}");
+                None
+            },
+        };
+
+        // Never left, unlike the groups joined on other interfaces
+        let loopback_multicast = match &socket_v4 {
+            Some(socket) => match socket.join_multicast_v4(&MULTICAST_V4_GROUP, &Ipv4Addr::LOCALHOST) {
+                Ok(()) => true,
+                Err(e) => {
+                    log::warn!("Could not join {MULTICAST_V4_GROUP} on loopback, other instances on this machine may not hear us: {e}");
+                    false
+                },
+            },
+            None => false,
+        };
+
+        let socket_v6 = match bind_v6_socket() {
+            Ok(socket) => Some(socket),
+            Err(e) => {
+                log::warn!("Could not create IPv6 discovery socket, IPv6 discovery disabled: {e}");
+                None
+            },
+        };
+
+        Discovery {
+            socket_v4,
+            socket_v6,
+            config: Mutex::new(DiscoveryConfig::default()),
+            loopback_multicast,
+            joined_v4: Mutex::new(HashSet::new()),
+            joined_v6: Mutex::new(HashSet::new()),
+        }
+    }
+
+    pub fn config(&self) -> DiscoveryConfig {
+        self.config.lock().unwrap().clone()
+    }
+
+    pub fn set_config(&self, config: DiscoveryConfig) {
+        if !config.multicast_v4 {
+            if let Some(socket_v4) = &self.socket_v4 {
+                for iface_ip in self.joined_v4.lock().unwrap().drain() {
+                    let _ = socket_v4.leave_multicast_v4(&MULTICAST_V4_GROUP, &iface_ip);
+                }
+            }
+        }
+        if !config.multicast_v6 {
+            if let Some(socket_v6) = &self.socket_v6 {
+                for index in self.joined_v6.lock().unwrap().drain() {
+                    let _ = socket_v6.leave_multicast_v6(&MULTICAST_V6_GROUP, index);
+                }
+            }
+        }
+
+        *self.config.lock().unwrap() = config;
+    }
+
+    // Joins the multicast groups of every enabled transport on every
+    // interface that hasn't been excluded, so we hear announcements sent to
+    // them. Sending to a group doesn't need this, only receiving.
+    pub fn join_groups(&self, excluded: &HashSet<String>) {
+        let config = self.config();
+
+        if let (true, Some(socket_v4)) = (config.multicast_v4, &self.socket_v4) {
+            let sock_ref = SockRef::from(socket_v4);
+            let mut joined_v4 = self.joined_v4.lock().unwrap();
+            let ifaces_v4 = interfaces::get_local_interfaces();
+            for iface in ifaces_v4.iter().filter(|iface| !excluded.contains(&iface.name)) {
+                if !joined_v4.contains(&iface.ip) {
+                    match sock_ref.join_multicast_v4(&MULTICAST_V4_GROUP, &iface.ip) {
+                        Ok(()) => {
+                            log::info!("Joined {MULTICAST_V4_GROUP} on {}", iface.name);
+                            joined_v4.insert(iface.ip);
+                        },
+                        Err(e) => log::warn!("Could not join {MULTICAST_V4_GROUP} on {}: {e}", iface.name),
+                    }
+                }
+            }
+        }
+
+        if config.multicast_v6 {
+            if let Some(socket_v6) = &self.socket_v6 {
+                let mut joined_v6 = self.joined_v6.lock().unwrap();
+                for (name, index) in interfaces::get_link_local_v6_interfaces() {
+                    if excluded.contains(&name) || joined_v6.contains(&index) {
+                        continue;
+                    }
+                    match socket_v6.join_multicast_v6(&MULTICAST_V6_GROUP, index) {
+                        Ok(()) => {
+                            log::info!("Joined {MULTICAST_V6_GROUP} on {name}");
+                            joined_v6.insert(index);
+                        },
+                        Err(e) => log::warn!("Could not join {MULTICAST_V6_GROUP} on {name}: {e}"),
+                    }
+                }
+            }
+        }
+    }
+
+    // Sends msg out of every enabled transport on every interface that
+    // hasn't been excluded. It is always sent over loopback too, for other
+    // instances on this machine when there is no network to broadcast on.
+    pub fn announce(&self, msg: &[u8], excluded: &HashSet<String>) {
+        let config = self.config();
+
+        if let Some(socket_v4) = &self.socket_v4 {
+            self.announce_v4(socket_v4, msg, excluded, &config);
+        }
+
+        if config.multicast_v6 {
+            if let Some(socket_v6) = &self.socket_v6 {
+                for (name, index) in interfaces::get_link_local_v6_interfaces() {
+                    if excluded.contains(&name) {
+                        continue;
+                    }
+
+                    // the scope id picks which interface a link-local packet goes out of
+                    let dest = SocketAddrV6::new(MULTICAST_V6_GROUP, DISCOVERY_PORT, 0, index);
+                    send(socket_v6, msg, SocketAddr::V6(dest));
+                }
+            }
+        }
+    }
+
+    fn announce_v4(&self, socket_v4: &UdpSocket, msg: &[u8], excluded: &HashSet<String>, config: &DiscoveryConfig) {
+        let sock_ref = SockRef::from(socket_v4);
+
+        // Unicast to loopback only reaches one of the sockets sharing the port,
+        // but multicast reaches all of them
+        if self.loopback_multicast {
+            match sock_ref.set_multicast_if_v4(&Ipv4Addr::LOCALHOST) {
+                Ok(()) => send(socket_v4, msg, SocketAddr::new(MULTICAST_V4_GROUP.into(), DISCOVERY_PORT)),
+                Err(e) => log::warn!("Could not send multicast out of loopback: {e}"),
+            }
+        } else {
+            send(socket_v4, msg, SocketAddr::new(Ipv4Addr::LOCALHOST.into(), DISCOVERY_PORT));
+        }
+
+        let ifaces_v4: Vec<interfaces::LocalInterface> = interfaces::get_local_interfaces()
+            .into_iter()
+            .filter(|iface| !excluded.contains(&iface.name))
+            .collect();
+
+        if config.broadcast {
+            // Directed broadcasts go out of the interface for their subnet, unlike
+            // 255.255.255.255 which only goes out of whichever one the OS picks
+            for iface in &ifaces_v4 {
+                send(socket_v4, msg, SocketAddr::new(iface.broadcast.into(), DISCOVERY_PORT));
+            }
+        }
+
+        if config.multicast_v4 {
+            for iface in &ifaces_v4 {
+                if let Err(e) = sock_ref.set_multicast_if_v4(&iface.ip) {
+                    log::warn!("Could not send multicast out of {}: {e}", iface.name);
+                    continue;
+                }
+                send(socket_v4, msg, SocketAddr::new(MULTICAST_V4_GROUP.into(), DISCOVERY_PORT));
+            }
+        }
+    }
+
+    // Everything that has arrived on any transport since we last checked.
+    // Addresses of IPv6 peers keep their scope id, so they can be connected to.
+    pub fn recv_all(&self) -> Vec<(Vec<u8>, SocketAddr)> {
+        let mut datagrams = Vec::new();
+        let mut buf = [0; 1024]; // discovery msgs are small, at most the broadcast limit of 1 KiB
+
+        let sockets = self.socket_v4.iter().chain(self.socket_v6.as_ref());
+        for socket in sockets {
+            while let Ok((received, rec_saddr)) = socket.recv_from(&mut buf) {
+                datagrams.push((buf[..received].to_vec(), rec_saddr));
+            }
+        }
+
+        datagrams
+    }
+}
+
+// Every instance on this machine shares the discovery port, so they all
+// need to reuse it. Broadcast and multicast datagrams go to all of them.
+// BSDs (including macOS) only let a port be shared with SO_REUSEPORT.
+fn bind_v4_socket() -> std::io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.bind(&SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), DISCOVERY_PORT).into())?;
+    socket.set_broadcast(true)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket.into())
+}
+
+fn bind_v6_socket() -> std::io::Result<UdpSocket> {
+    // Has to be v6 only, otherwise it would fight the v4 socket for the port
+    let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_only_v6(true)?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.bind(&SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), DISCOVERY_PORT).into())?;
+    socket.set_nonblocking(true)?;
+    Ok(socket.into())
+}
+
+fn send(socket: &UdpSocket, msg: &[u8], dest: SocketAddr) {
+    match socket.send_to(msg, dest) {
+        Ok(bytes_written) => {
+            if bytes_written != msg.len() {
+                log::error!("Error: could not send entire discovery message to {dest}");
+            }
+        },
+        Err(e) => {
+            log::error!("Error sending discovery message to {dest}: {e:#?}");
+        },
+    };
+}
+
+#[tauri::command]
+pub fn cmd_get_discovery_config(state: State<AppState>) -> DiscoveryConfig {
+    state.connection.discovery.config()
+}
+
+#[tauri::command]
+pub fn cmd_set_discovery_config(config: DiscoveryConfig, state: State<AppState>) {
+    log::info!("Updating discovery config: {config:?}");
+    if !config.mdns {
+        state.connection.mdns.stop();
+    }
+    state.connection.discovery.set_config(config);
+}
@@ -0,0 +1,375 @@
+// Files offered in the chat. An offer is just a File message, and the file
+// itself is only sent to whoever accepts it, as FileParts answering their
+// FileRequest. Parts are written to disk as they arrive, so a download that
+// gets cut off by a drop picks up where it left off once we reconnect.
+
+use std::{fs, io::Write, path::{Path, PathBuf}, collections::HashMap};
+use serde::Serialize;
+use ts_rs::TS;
+use sha2::{Sha256, Digest};
+use tauri::{State, async_runtime};
+use tauri::api::dialog::blocking::FileDialogBuilder;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+use crate::message::{Message, MessageData, FileData, FILE_PART_LEN};
+use crate::peer::{PeerSender, Outgoing};
+use crate::utilities::{gen_rand_id, get_curr_time};
+use crate::AppState;
+
+#[derive(TS, Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[ts(export)]
+#[ts(export_to="../src/lib/bindings/")]
+pub enum FileState {
+    Offered,
+    Downloading,
+    Done, // received and matches the hash in the offer
+    Declined,
+    Failed,
+}
+
+// Sent to the frontend whenever a file someone offered us changes, and every
+// percent or so while it downloads. uid is whoever offered it.
+#[derive(TS, Serialize, Clone)]
+#[ts(export)]
+#[ts(export_to="../src/lib/bindings/")]
+pub struct FileStatus {
+    pub mid: u32,
+    pub uid: u32,
+    pub state: FileState,
+    pub received: u64,
+    pub size: u64,
+    pub error: Option<String>, // set if Failed
+}
+
+// A file someone else offered us
+struct IncomingFile {
+    offer: FileData,
+    state: FileState,
+    error: Option<String>,
+    received: u64,
+    // of everything received so far, parts always arrive in order
+    hasher: Sha256,
+    path: PathBuf, // where the download is written as it arrives
+    handle: Option<fs::File>, // open while downloading
+}
+
+impl IncomingFile {
+    fn status(&self) -> FileStatus {
+        FileStatus {
+            mid: self.offer.data.mid,
+            uid: self.offer.data.uid,
+            state: self.state,
+            received: self.received,
+            size: self.offer.size,
+            error: self.error.clone(),
+        }
+    }
+
+    fn percent(&self) -> u64 {
+        match self.offer.size {
+            0 => 100,
+            size => self.received * 100 / size,
+        }
+    }
+
+    // Nothing is kept of a failed download, it starts over if accepted again
+    fn fail(&mut self, error: String) {
+        log::warn!("Download of {} failed: {error}", self.offer.file_name);
+        self.state = FileState::Failed;
+        self.error = Some(error);
+        self.handle = None;
+        let _ = fs::remove_file(&self.path);
+    }
+
+    fn finish(&mut self) {
+        let hash = std::mem::take(&mut self.hasher).finalize();
+        if hash.as_slice() != self.offer.hash {
+            self.fail("file does not match its hash".to_owned());
+            return;
+        }
+        log::info!("Finished downloading {}", self.offer.file_name);
+        self.state = FileState::Done;
+        self.handle = None;
+    }
+}
+
+pub struct Files {
+    // Files we offered, by mid
+    offered: HashMap<u32, PathBuf>,
+    // Files offered to us, by mid
+    incoming: HashMap<u32, IncomingFile>,
+    // Downloads go here until the user saves them somewhere. Each instance
+    // gets its own, since several can run on one machine.
+    dir: PathBuf,
+}
+
+impl Files {
+    pub fn new() -> Files {
+        Files {
+            offered: HashMap::new(),
+            incoming: HashMap::new(),
+            dir: std::env::temp_dir().join(format!("ectochat-{}", std::process::id())),
+        }
+    }
+
+    pub fn add_offer(&mut self, offer: FileData, window: &tauri::Window) {
+        let mid = offer.data.mid;
+        let file = IncomingFile {
+            offer,
+            state: FileState::Offered,
+            error: None,
+            received: 0,
+            hasher: Sha256::new(),
+            path: self.dir.join(format!("{mid}.part")),
+            handle: None,
+        };
+        emit_status(file.status(), window);
+        self.incoming.insert(mid, file);
+    }
+
+    pub fn offered_path(&self, mid: u32) -> Option<PathBuf> {
+        self.offered.get(&mid).cloned()
+    }
+
+    // Starts downloading a file from the beginning. Returns who to send the
+    // FileRequest to.
+    pub fn accept(&mut self, mid: u32, window: &tauri::Window) -> Result<(u32, Message), String> {
+        fs::create_dir_all(&self.dir)
+            .map_err(|e| format!("Could not create {}: {e}", self.dir.display()))?;
+
+        let file = self.incoming.get_mut(&mid).ok_or_else(|| format!("No file offered with id {mid}"))?;
+        if matches!(file.state, FileState::Downloading | FileState::Done) {
+            return Err(format!("{} has already been accepted", file.offer.file_name));
+        }
+
+        let handle = fs::File::create(&file.path)
+            .map_err(|e| format!("Could not create {}: {e}", file.path.display()))?;
+        file.state = FileState::Downloading;
+        file.error = None;
+        file.received = 0;
+        file.hasher = Sha256::new();
+        file.handle = Some(handle);
+        log::info!("Downloading {} from uid={}", file.offer.file_name, file.offer.data.uid);
+
+        if file.offer.size == 0 {
+            file.finish(); // nothing to ask for
+        }
+        emit_status(file.status(), window);
+
+        Ok((file.offer.data.uid, Message::FileRequest { mid, offset: 0 }))
+    }
+
+    // Only affects us, whoever offered it never finds out
+    pub fn decline(&mut self, mid: u32, window: &tauri::Window) -> Result<(), String> {
+        let file = self.incoming.get_mut(&mid).ok_or_else(|| format!("No file offered with id {mid}"))?;
+        file.state = FileState::Declined;
+        file.error = None;
+        file.received = 0;
+        file.handle = None;
+        let _ = fs::remove_file(&file.path);
+        emit_status(file.status(), window);
+        Ok(())
+    }
+
+    // Anything that isn't the next part of a download from uid is ignored,
+    // e.g. parts still in flight from before we declined or reconnected
+    pub fn add_part(&mut self, uid: u32, mid: u32, offset: u64, data: &[u8], window: &tauri::Window) {
+        let file = match self.incoming.get_mut(&mid) {
+            Some(file) if file.offer.data.uid == uid && file.state == FileState::Downloading => file,
+            _ => {
+                log::debug!("Ignoring part of file {mid} from uid={uid}, which we aren't downloading");
+                return;
+            },
+        };
+        if offset != file.received {
+            log::debug!("Ignoring part of file {mid} at {offset}, expected {}", file.received);
+            return;
+        }
+
+        let percent = file.percent();
+        if file.received + data.len() as u64 > file.offer.size {
+            file.fail(format!("more than the {} bytes offered", file.offer.size));
+        } else if let Err(e) = file.handle.as_mut().map_or(Ok(()), |handle| handle.write_all(data)) {
+            file.fail(format!("could not write {}: {e}", file.path.display()));
+        } else {
+            file.hasher.update(data);
+            file.received += data.len() as u64;
+            if file.received == file.offer.size {
+                file.finish();
+            }
+        }
+
+        if file.state != FileState::Downloading || file.percent() != percent {
+            emit_status(file.status(), window);
+        }
+    }
+
+    // Asks for the rest of every download from uid that a drop cut off
+    pub fn resume_requests(&self, uid: u32) -> Vec<Message> {
+        self.incoming.values()
+            .filter(|file| file.offer.data.uid == uid && file.state == FileState::Downloading)
+            .map(|file| {
+                log::info!("Resuming download of {} at {} bytes", file.offer.file_name, file.received);
+                Message::FileRequest { mid: file.offer.data.mid, offset: file.received }
+            })
+            .collect()
+    }
+
+    // Where a finished download is, and what it should be called
+    fn downloaded(&self, mid: u32) -> Result<(PathBuf, String), String> {
+        match self.incoming.get(&mid) {
+            Some(file) if file.state == FileState::Done => {
+                // the name came from a peer, so make sure it can't point anywhere else
+                let file_name = Path::new(&file.offer.file_name).file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+                    .unwrap_or_else(|| "download".to_owned());
+                Ok((file.path.clone(), file_name))
+            },
+            Some(file) => Err(format!("{} hasn't finished downloading", file.offer.file_name)),
+            None => Err(format!("No file offered with id {mid}")),
+        }
+    }
+}
+
+fn emit_status(status: FileStatus, window: &tauri::Window) {
+    if let Err(e) = window.emit("evt_file_changed", status) {
+        log::error!("evt_file_changed err {e:#?}");
+    }
+}
+
+// Streams an offered file to whoever asked for it, from offset on. Waits on
+// their media lane like any other sender, so chat keeps flowing around it.
+pub async fn send_file(path: PathBuf, mid: u32, mut offset: u64, writer: PeerSender) {
+    let mut file = match tokio::fs::File::open(&path).await {
+        Ok(file) => file,
+        Err(e) => {
+            log::error!("Could not open {} to send it: {e}", path.display());
+            return;
+        },
+    };
+    if let Err(e) = file.seek(std::io::SeekFrom::Start(offset)).await {
+        log::error!("Could not seek to {offset} in {}: {e}", path.display());
+        return;
+    }
+
+    loop {
+        let mut data = vec![0; FILE_PART_LEN];
+        let len = match file.read(&mut data).await {
+            Ok(0) => break,
+            Ok(len) => len,
+            Err(e) => {
+                log::error!("Error reading {}: {e}", path.display());
+                return;
+            },
+        };
+        data.truncate(len);
+
+        if writer.send(Outgoing::Msg(Message::FilePart { mid, offset, data })).await.is_err() {
+            log::info!("Connection closed while sending {}, stopping at {offset} bytes", path.display());
+            return;
+        }
+        offset += len as u64;
+    }
+    log::info!("Finished sending {}", path.display());
+}
+
+// Size and sha256 of the file at path
+fn hash_file(path: &Path) -> std::io::Result<(u64, Vec<u8>)> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let size = std::io::copy(&mut file, &mut hasher)?;
+    Ok((size, hasher.finalize().to_vec()))
+}
+
+// Only used so the other side can show an icon or preview, so a handful of
+// common types is plenty
+fn guess_mime(path: &Path) -> &'static str {
+    let ext = path.extension().map(|ext| ext.to_string_lossy().to_ascii_lowercase());
+    match ext.as_deref() {
+        Some("txt") => "text/plain",
+        Some("md") => "text/markdown",
+        Some("csv") => "text/csv",
+        Some("html" | "htm") => "text/html",
+        Some("json") => "application/json",
+        Some("pdf") => "application/pdf",
+        Some("zip") => "application/zip",
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("svg") => "image/svg+xml",
+        Some("mp3") => "audio/mpeg",
+        Some("wav") => "audio/wav",
+        Some("ogg") => "audio/ogg",
+        Some("mp4") => "video/mp4",
+        Some("webm") => "video/webm",
+        _ => "application/octet-stream",
+    }
+}
+
+// Asks the user for a file and offers it to everyone. Async so the dialog
+// and hashing don't block the main thread.
+#[tauri::command]
+pub async fn cmd_offer_file(state: State<'_, AppState>) -> Result<(), String> {
+    let picked = async_runtime::spawn_blocking(|| {
+        FileDialogBuilder::new().set_title("Send a file").pick_file()
+    }).await.map_err(|e| e.to_string())?;
+    let path = match picked {
+        Some(path) => path,
+        None => return Ok(()), // cancelled
+    };
+
+    let hash_path = path.clone();
+    let (size, hash) = async_runtime::spawn_blocking(move || hash_file(&hash_path))
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| format!("Could not read {}: {e}", path.display()))?;
+
+    let (name, uid) = {
+        let profile = state.profile.lock().unwrap();
+        (profile.name.clone(), profile.uid)
+    };
+    let mid = gen_rand_id();
+    let msg = state.identity.lock().unwrap().sign(Message::File(Box::new(FileData {
+        data: MessageData::new(name, uid, mid, get_curr_time(), Vec::new()),
+        file_name: path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default(),
+        size,
+        mime: guess_mime(&path).to_owned(),
+        hash,
+    })));
+
+    log::info!("Offering {} ({size} bytes)", path.display());
+    state.files.lock().unwrap().offered.insert(mid, path);
+    state.connection.send(vec![msg]).await
+}
+
+#[tauri::command]
+pub async fn cmd_accept_file(mid: u32, window: tauri::Window, state: State<'_, AppState>) -> Result<(), String> {
+    let (uid, request) = state.files.lock().unwrap().accept(mid, &window)?;
+    // If they aren't connected right now, this is sent once they are back
+    state.connection.send_to(Some(vec![uid]), vec![request]).await
+}
+
+#[tauri::command]
+pub fn cmd_decline_file(mid: u32, window: tauri::Window, state: State<AppState>) -> Result<(), String> {
+    state.files.lock().unwrap().decline(mid, &window)
+}
+
+// Asks the user where to put a finished download and copies it there
+#[tauri::command]
+pub async fn cmd_save_file(mid: u32, state: State<'_, AppState>) -> Result<(), String> {
+    let (path, file_name) = state.files.lock().unwrap().downloaded(mid)?;
+
+    let picked = async_runtime::spawn_blocking(move || {
+        FileDialogBuilder::new().set_title("Save file").set_file_name(&file_name).save_file()
+    }).await.map_err(|e| e.to_string())?;
+    let dest = match picked {
+        Some(dest) => dest,
+        None => return Ok(()), // cancelled
+    };
+
+    tokio::fs::copy(&path, &dest).await
+        .map_err(|e| format!("Could not save to {}: {e}", dest.display()))?;
+    log::info!("Saved file {mid} to {}", dest.display());
+    Ok(())
+}
@@ -0,0 +1,268 @@
+// Private group conversations among some of the peers we are connected to.
+// There is no owner, any member can change who is in a group. Every change
+// is sent to everyone who was or is now in it (including ourselves), and
+// everyone applies it the same way when it arrives.
+
+use std::collections::HashMap;
+use serde::Serialize;
+use ts_rs::TS;
+use tauri::State;
+
+use crate::identity::Identity;
+use crate::message::{Message, MessageData, GroupData, GroupMsgData};
+use crate::profile::Profile;
+use crate::utilities::{gen_rand_id, get_curr_time, parse_img_str};
+use crate::AppState;
+
+const MAX_GROUP_MEMBERS: usize = 64;
+const MAX_GROUP_NAME_LEN: usize = 64;
+
+#[derive(TS, Serialize, Clone, Debug)]
+#[ts(export)]
+#[ts(export_to="../src/lib/bindings/")]
+pub struct Group {
+    pub gid: u32,
+    pub name: String,
+    pub members: Vec<u32>,
+}
+
+// Every group we are in, by gid
+pub struct Groups {
+    groups: HashMap<u32, Group>,
+}
+
+impl Groups {
+    pub fn new() -> Groups {
+        Groups {
+            groups: HashMap::new(),
+        }
+    }
+
+    pub fn list(&self) -> Vec<Group> {
+        self.groups.values().cloned().collect()
+    }
+
+    pub fn get(&self, gid: u32) -> Option<&Group> {
+        self.groups.get(&gid)
+    }
+
+    pub fn is_member(&self, gid: u32, uid: u32) -> bool {
+        self.groups.get(&gid).map_or(false, |group| group.members.contains(&uid))
+    }
+
+    // Groups both us and uid are in
+    pub fn shared_with(&self, uid: u32) -> Vec<Group> {
+        self.groups.values()
+            .filter(|group| group.members.contains(&uid))
+            .cloned()
+            .collect()
+    }
+
+    // Applies a GroupCreate or GroupUpdate, which the caller has checked came
+    // from its author. Changes to a group we know about are only taken from
+    // its members, and we only start tracking groups we are in. Returns
+    // whether anything changed, so the caller can tell the frontend.
+    pub fn apply(&mut self, update: &GroupData, own_uid: u32) -> bool {
+        let sender = update.data.uid;
+        let is_member = match self.groups.get(&update.gid) {
+            Some(group) => group.members.contains(&sender),
+            None => update.members.contains(&sender),
+        };
+        if !is_member {
+            log::warn!("Ignoring change to group {} from uid={sender}, who isn't in it", update.gid);
+            return false;
+        }
+
+        let mut members = update.members.clone();
+        members.sort();
+        members.dedup();
+        if members.len() > MAX_GROUP_MEMBERS {
+            log::warn!("Ignoring change to group {} with {} members", update.gid, members.len());
+            return false;
+        }
+
+        if members.contains(&own_uid) {
+            log::info!("Group {} ({}) now has members {members:?}", update.gid, update.group_name);
+            self.groups.insert(update.gid, Group { gid: update.gid, name: update.group_name.clone(), members });
+        } else if self.groups.remove(&update.gid).is_some() {
+            log::info!("No longer in group {} ({})", update.gid, update.group_name);
+        } else {
+            return false; // about a group we aren't in
+        }
+        true
+    }
+}
+
+// The whole group as it stands, for sending to a member who may have missed
+// a change while they weren't connected
+pub fn make_update_msg(profile: &Profile, identity: &Identity, group: &Group) -> Message {
+    identity.sign(Message::GroupUpdate(Box::new(GroupData {
+        data: MessageData::new(profile.name.clone(), profile.uid, gen_rand_id(), get_curr_time(), Vec::new()),
+        gid: group.gid,
+        group_name: group.name.clone(),
+        members: group.members.clone(),
+    })))
+}
+
+fn new_msg_data(state: &State<'_, AppState>, payload: Vec<u8>) -> MessageData {
+    let profile = state.profile.lock().unwrap();
+    MessageData::new(profile.name.clone(), profile.uid, gen_rand_id(), get_curr_time(), payload)
+}
+
+// Members are sorted and deduplicated, and always include us
+fn check_members(mut members: Vec<u32>, own_uid: u32) -> Result<Vec<u32>, String> {
+    members.push(own_uid);
+    members.sort();
+    members.dedup();
+    if members.len() < 2 {
+        return Err("A group needs at least one other member".to_owned());
+    }
+    if members.len() > MAX_GROUP_MEMBERS {
+        return Err(format!("Groups can have at most {MAX_GROUP_MEMBERS} members"));
+    }
+    Ok(members)
+}
+
+// Returns the gid of the new group
+#[tauri::command]
+pub async fn cmd_create_group(name: String, members: Vec<u32>, state: State<'_, AppState>) -> Result<u32, String> {
+    let name = name.trim().to_owned();
+    if name.is_empty() || name.len() > MAX_GROUP_NAME_LEN {
+        return Err(format!("Group names must be between 1 and {MAX_GROUP_NAME_LEN} bytes"));
+    }
+
+    let data = new_msg_data(&state, Vec::new());
+    let members = check_members(members, data.uid)?;
+    let gid = gen_rand_id();
+
+    // We start tracking it once it comes back to us, like everyone else
+    let msg = state.identity.lock().unwrap().sign(
+        Message::GroupCreate(Box::new(GroupData { data, gid, group_name: name, members: members.clone() }))
+    );
+    state.connection.send_to(Some(members), vec![msg]).await?;
+    Ok(gid)
+}
+
+// Sets who is in a group we are in. Anyone taken out is told, so they stop
+// expecting messages from it.
+#[tauri::command]
+pub async fn cmd_set_group_members(gid: u32, members: Vec<u32>, state: State<'_, AppState>) -> Result<(), String> {
+    let data = new_msg_data(&state, Vec::new());
+    let members = check_members(members, data.uid)?;
+
+    let (group_name, mut to) = {
+        let groups = state.groups.lock().unwrap();
+        let group = groups.get(gid).ok_or_else(|| format!("Not in a group with id {gid}"))?;
+        (group.name.clone(), group.members.clone())
+    };
+    to.extend(&members);
+
+    let msg = state.identity.lock().unwrap().sign(
+        Message::GroupUpdate(Box::new(GroupData { data, gid, group_name, members }))
+    );
+    state.connection.send_to(Some(to), vec![msg]).await
+}
+
+#[tauri::command]
+pub async fn cmd_leave_group(gid: u32, state: State<'_, AppState>) -> Result<(), String> {
+    let data = new_msg_data(&state, Vec::new());
+
+    let (group_name, to) = {
+        let groups = state.groups.lock().unwrap();
+        let group = groups.get(gid).ok_or_else(|| format!("Not in a group with id {gid}"))?;
+        (group.name.clone(), group.members.clone())
+    };
+    let members = to.iter().copied().filter(|uid| *uid != data.uid).collect();
+
+    let msg = state.identity.lock().unwrap().sign(
+        Message::GroupUpdate(Box::new(GroupData { data, gid, group_name, members }))
+    );
+    state.connection.send_to(Some(to), vec![msg]).await
+}
+
+#[tauri::command]
+pub fn cmd_list_groups(state: State<AppState>) -> Vec<Group> {
+    state.groups.lock().unwrap().list()
+}
+
+async fn send_to_group(gid: u32, msg: Message, state: State<'_, AppState>) -> Result<(), String> {
+    let members = state.groups.lock().unwrap().get(gid)
+        .map(|group| group.members.clone())
+        .ok_or_else(|| format!("Not in a group with id {gid}"))?;
+    let msg = state.identity.lock().unwrap().sign(msg);
+    state.connection.send_to(Some(members), vec![msg]).await
+}
+
+#[tauri::command]
+pub async fn cmd_send_group_text(gid: u32, msg: String, state: State<'_, AppState>) -> Result<(), String> {
+    let data = new_msg_data(&state, msg.as_bytes().to_vec());
+    send_to_group(gid, Message::GroupText(GroupMsgData { data, gid }), state).await
+}
+
+#[tauri::command]
+pub async fn cmd_send_group_img(gid: u32, img: String, state: State<'_, AppState>) -> Result<(), String> {
+    let data = new_msg_data(&state, parse_img_str(&img));
+    send_to_group(gid, Message::GroupImage(GroupMsgData { data, gid }), state).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ME: u32 = 1;
+    const GID: u32 = 100;
+
+    fn update(sender: u32, members: &[u32]) -> GroupData {
+        GroupData {
+            data: MessageData::new("someone".to_owned(), sender, 42, 1_700_000_000, Vec::new()),
+            gid: GID,
+            group_name: "friends".to_owned(),
+            members: members.to_vec(),
+        }
+    }
+
+    #[test]
+    fn tracks_groups_we_are_added_to() {
+        let mut groups = Groups::new();
+        assert!(groups.apply(&update(2, &[3, 2, ME, 3]), ME));
+        assert_eq!(groups.get(GID).unwrap().members, vec![ME, 2, 3]);
+        assert!(groups.is_member(GID, 3));
+        assert_eq!(groups.shared_with(2).len(), 1);
+    }
+
+    #[test]
+    fn ignores_groups_we_are_not_in() {
+        let mut groups = Groups::new();
+        assert!(!groups.apply(&update(2, &[2, 3]), ME));
+        assert!(groups.list().is_empty());
+    }
+
+    #[test]
+    fn only_takes_changes_from_members() {
+        let mut groups = Groups::new();
+        // can't start a group for others without being in it
+        assert!(!groups.apply(&update(2, &[ME, 3]), ME));
+
+        assert!(groups.apply(&update(2, &[ME, 2]), ME));
+        assert!(!groups.apply(&update(3, &[ME, 2, 3]), ME));
+        assert!(!groups.is_member(GID, 3));
+    }
+
+    #[test]
+    fn forgets_groups_we_are_removed_from() {
+        let mut groups = Groups::new();
+        assert!(groups.apply(&update(2, &[ME, 2]), ME));
+        assert!(groups.apply(&update(2, &[2]), ME));
+        assert!(groups.get(GID).is_none());
+        // nothing left to change
+        assert!(!groups.apply(&update(2, &[2]), ME));
+    }
+
+    #[test]
+    fn rejects_too_many_members() {
+        let mut groups = Groups::new();
+        let members: Vec<u32> = (1..=MAX_GROUP_MEMBERS as u32 + 1).collect();
+        assert!(!groups.apply(&update(2, &members), ME));
+        assert!(groups.apply(&update(2, &members[..MAX_GROUP_MEMBERS]), ME));
+    }
+}
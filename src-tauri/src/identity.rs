@@ -0,0 +1,330 @@
+// Our long term identity. Uids are random each launch, and anyone can put
+// any name and uid in a message, so on their own they prove nothing. Instead
+// each install has an Ed25519 keypair kept in the app config directory. Its
+// public key goes in our Hello, and every message of ours that says who it's
+// from (Text, Image, Goodbye, File, Audio, and the private and group ones) is
+// signed with it, so nobody else can send those as us.
+//
+// The Hello also carries a signature over the connection's Noise handshake
+// hash, which proves the key belongs to whoever is on the other end, and a
+// key that isn't proven is ignored.
+//
+// Messages from someone whose Hello gave us a key have to be signed with it,
+// or they are dropped. Older builds don't sign anything, so their messages
+// are let through, but never marked as verified.
+
+use std::{fs, path::Path};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use rand::rngs::OsRng;
+
+use crate::message::{HelloData, Message, MessageData};
+use crate::utilities::KnownUsers;
+
+const IDENTITY_FILE: &str = "identity.key";
+// So a Hello signature can never be mistaken for a message signature
+const HELLO_CONTEXT: &[u8] = b"ectochat hello";
+
+pub struct Identity {
+    key: SigningKey,
+}
+
+impl Identity {
+    // Only kept for this launch, until load replaces it
+    pub fn generate() -> Identity {
+        Identity { key: SigningKey::generate(&mut OsRng) }
+    }
+
+    // Makes a new one the first time the app runs
+    pub fn load(app: &tauri::AppHandle) -> Identity {
+        let path = match app.path_resolver().app_config_dir() {
+            Some(dir) => dir.join(IDENTITY_FILE),
+            None => {
+                log::error!("Could not find app config dir, identity will not be saved");
+                return Identity::generate();
+            },
+        };
+
+        match fs::read(&path) {
+            Ok(bytes) => match <[u8; 32]>::try_from(bytes.as_slice()) {
+                Ok(bytes) => {
+                    log::info!("Loaded identity from {}", path.display());
+                    return Identity { key: SigningKey::from_bytes(&bytes) };
+                },
+                // Don't overwrite it, in case it can be recovered
+                Err(_) => {
+                    log::error!("{} is not a valid identity, using a new one for now", path.display());
+                    return Identity::generate();
+                },
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {},
+            Err(e) => {
+                log::error!("Error reading {}, using a new identity for now: {e}", path.display());
+                return Identity::generate();
+            },
+        }
+
+        let identity = Identity::generate();
+        match save_key(&path, &identity.key) {
+            Ok(()) => log::info!("Created new identity in {}", path.display()),
+            Err(e) => log::error!("Error writing {}: {e}", path.display()),
+        }
+        identity
+    }
+
+    pub fn public_key(&self) -> Vec<u8> {
+        self.key.verifying_key().to_bytes().to_vec()
+    }
+
+    // Proves the key in our Hello is ours, and ties it to this connection's
+    // Noise keys, so it can't be replayed on another connection
+    pub fn sign_hello(&self, handshake_hash: &[u8], dialed: bool, uid: u32) -> Vec<u8> {
+        self.key.sign(&hello_bytes(handshake_hash, dialed, uid)).to_bytes().to_vec()
+    }
+
+    // Signs msg if it's one we sign, otherwise leaves it alone
+    pub fn sign(&self, mut msg: Message) -> Message {
+        let msg_type = msg.get_type_str().to_owned();
+        if let Some((data, extra)) = signed_parts(&mut msg) {
+            data.signature = self.key.sign(&signed_bytes(&msg_type, data, &extra)).to_bytes().to_vec();
+        }
+        msg
+    }
+}
+
+fn save_key(path: &Path, key: &SigningKey) -> std::io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600); // only readable by us
+    }
+    std::io::Write::write_all(&mut options.open(path)?, &key.to_bytes())
+}
+
+// The MessageData of msg if it's one we sign, along with the fields it
+// carries outside of it, so those can't be changed either
+fn signed_parts(msg: &mut Message) -> Option<(&mut MessageData, Vec<u8>)> {
+    let mut extra = Vec::new();
+    let data = match msg {
+        Message::Text(data) | Message::Image(data) | Message::Goodbye(data) => data,
+        Message::File(file) => {
+            push_bytes(&mut extra, file.file_name.as_bytes());
+            extra.extend(file.size.to_le_bytes());
+            push_bytes(&mut extra, file.mime.as_bytes());
+            push_bytes(&mut extra, &file.hash);
+            &mut file.data
+        },
+        Message::Audio(audio) => {
+            extra.extend(audio.duration_ms.to_le_bytes());
+            extra.extend(audio.sample_rate.to_le_bytes());
+            &mut audio.data
+        },
+        Message::DirectText(direct) | Message::DirectImage(direct) => {
+            extra.extend(direct.to.to_le_bytes());
+            &mut direct.data
+        },
+        Message::GroupCreate(group) | Message::GroupUpdate(group) => {
+            extra.extend(group.gid.to_le_bytes());
+            push_bytes(&mut extra, group.group_name.as_bytes());
+            extra.extend((group.members.len() as u32).to_le_bytes());
+            for uid in &group.members {
+                extra.extend(uid.to_le_bytes());
+            }
+            &mut group.data
+        },
+        Message::GroupText(group) | Message::GroupImage(group) => {
+            extra.extend(group.gid.to_le_bytes());
+            &mut group.data
+        },
+        _ => return None,
+    };
+    Some((data, extra))
+}
+
+fn push_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend((bytes.len() as u32).to_le_bytes());
+    out.extend_from_slice(bytes);
+}
+
+// Everything about the message, so none of it can be changed or passed off
+// as another type. Strings and bytes are prefixed with their length.
+fn signed_bytes(msg_type: &str, data: &MessageData, extra: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    push_bytes(&mut out, msg_type.as_bytes());
+    push_bytes(&mut out, data.name.as_bytes());
+    out.extend(data.uid.to_le_bytes());
+    out.extend(data.mid.to_le_bytes());
+    out.extend(data.timestamp.to_le_bytes());
+    push_bytes(&mut out, &data.payload);
+    out.extend_from_slice(extra);
+    out
+}
+
+// dialed is whether whoever signed it dialed the connection, so the two
+// sides' signatures aren't interchangeable
+fn hello_bytes(handshake_hash: &[u8], dialed: bool, uid: u32) -> Vec<u8> {
+    let mut out = HELLO_CONTEXT.to_vec();
+    push_bytes(&mut out, handshake_hash);
+    out.push(dialed as u8);
+    out.extend(uid.to_le_bytes());
+    out
+}
+
+// Checks that whoever sent hello holds the private key for the public key in
+// it, returning whether they proved it. They can't over a plaintext
+// connection, or if they are an older build. A uid that already has a key is
+// never let in with an unproven or different one, so nobody can take over
+// someone else's uid. dialed is whether we dialed the connection.
+pub fn check_hello(
+    hello: &HelloData,
+    handshake_hash: Option<&[u8]>,
+    dialed: bool,
+    known_users: &KnownUsers,
+) -> Result<bool, String> {
+    let uid = hello.data.uid;
+    let proven = match handshake_hash {
+        Some(handshake_hash) if !hello.public_key.is_empty() && !hello.handshake_sig.is_empty() => {
+            let public_key = VerifyingKey::try_from(hello.public_key.as_slice())
+                .map_err(|e| format!("Bad public key: {e}"))?;
+            let signature = Signature::from_slice(&hello.handshake_sig)
+                .map_err(|_| "Hello signature is malformed".to_owned())?;
+            public_key.verify(&hello_bytes(handshake_hash, !dialed, uid), &signature)
+                .map_err(|_| "Hello has a bad signature".to_owned())?;
+            true
+        },
+        _ => false,
+    };
+
+    match known_users.public_key(uid) {
+        Some(known) if !known.is_empty() && (!proven || known != hello.public_key.as_slice()) => {
+            Err(format!("uid {uid:x} already belongs to someone else"))
+        },
+        _ => Ok(proven),
+    }
+}
+
+// Checks the signature on msg against the key of whoever it says it's from,
+// and marks it as verified if it checks out. Returns why if it should be
+// dropped instead. Messages can be resent by others after a drop, so this
+// goes by the uid in the message rather than who we got it from.
+pub fn check(msg: &mut Message, known_users: &KnownUsers) -> Result<(), String> {
+    let msg_type = msg.get_type_str().to_owned();
+    let (data, extra) = match signed_parts(msg) {
+        Some(parts) => parts,
+        None => return Ok(()),
+    };
+
+    let public_key = match known_users.public_key(data.uid) {
+        Some(public_key) if !public_key.is_empty() => public_key,
+        _ => return Ok(()), // an older build, or someone we haven't heard Hello from
+    };
+    let public_key = VerifyingKey::try_from(public_key)
+        .map_err(|e| format!("uid={} has a bad public key: {e}", data.uid))?;
+    let signature = Signature::from_slice(&data.signature)
+        .map_err(|_| format!("{msg_type} message from uid={} is not signed", data.uid))?;
+    public_key.verify(&signed_bytes(&msg_type, data, &extra), &signature)
+        .map_err(|_| format!("{msg_type} message from uid={} has a bad signature", data.uid))?;
+
+    data.verified = true;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::profile::Profile;
+    use crate::rooms::DEFAULT_ROOM;
+
+    const UID: u32 = 7;
+    const HASH: [u8; 32] = [1; 32];
+
+    fn data() -> MessageData {
+        MessageData::new("someone".to_owned(), UID, 42, 1_700_000_000, b"hi".to_vec())
+    }
+
+    // Signed as if by whoever dialed the connection
+    fn hello(identity: &Identity) -> HelloData {
+        let sig = identity.sign_hello(&HASH, true, UID);
+        HelloData::new(data(), 61000, DEFAULT_ROOM.to_owned(), identity.public_key(), sig, Vec::new())
+    }
+
+    // add_user needs a window to tell the frontend, so go around it
+    fn known(public_key: Vec<u8>) -> KnownUsers {
+        let mut profile = Profile::new("someone".to_owned());
+        profile.uid = UID;
+        profile.public_key = public_key;
+        serde_json::from_value(serde_json::json!({
+            "uid_to_profile": { UID.to_string(): profile },
+            "uid_to_key": {},
+        })).unwrap()
+    }
+
+    #[test]
+    fn proves_the_key_in_a_hello() {
+        let identity = Identity::generate();
+        assert_eq!(check_hello(&hello(&identity), Some(&HASH), false, &KnownUsers::new()), Ok(true));
+    }
+
+    #[test]
+    fn hello_signatures_only_work_for_the_connection_they_were_made_on() {
+        let identity = Identity::generate();
+        let hello = hello(&identity);
+        // the dialer's signature can't be passed off as the other side's
+        assert!(check_hello(&hello, Some(&HASH), true, &KnownUsers::new()).is_err());
+        assert!(check_hello(&hello, Some(&[2; 32]), false, &KnownUsers::new()).is_err());
+
+        let mut other_uid = hello.clone();
+        other_uid.data.uid = UID + 1;
+        assert!(check_hello(&other_uid, Some(&HASH), false, &KnownUsers::new()).is_err());
+    }
+
+    #[test]
+    fn unproven_keys_are_let_in_unless_the_uid_has_one() {
+        let identity = Identity::generate();
+        let mut unsigned = hello(&identity);
+        unsigned.handshake_sig.clear();
+        assert_eq!(check_hello(&unsigned, Some(&HASH), false, &KnownUsers::new()), Ok(false));
+        // plaintext, so there's nothing to sign
+        assert_eq!(check_hello(&hello(&identity), None, false, &KnownUsers::new()), Ok(false));
+
+        let known_users = known(identity.public_key());
+        assert!(check_hello(&unsigned, Some(&HASH), false, &known_users).is_err());
+        assert!(check_hello(&hello(&identity), None, false, &known_users).is_err());
+        assert_eq!(check_hello(&hello(&identity), Some(&HASH), false, &known_users), Ok(true));
+    }
+
+    #[test]
+    fn known_uids_cant_change_keys() {
+        let known_users = known(Identity::generate().public_key());
+        assert!(check_hello(&hello(&Identity::generate()), Some(&HASH), false, &known_users).is_err());
+    }
+
+    #[test]
+    fn checks_message_signatures() {
+        let identity = Identity::generate();
+        let known_users = known(identity.public_key());
+
+        let mut msg = identity.sign(Message::Text(data()));
+        assert_eq!(check(&mut msg, &known_users), Ok(()));
+        assert!(msg.chat_data().unwrap().verified);
+
+        let mut tampered = identity.sign(Message::Text(data()));
+        if let Message::Text(ref mut data) = tampered {
+            data.payload = b"bye".to_vec();
+        }
+        assert!(check(&mut tampered, &known_users).is_err());
+
+        let mut unsigned = Message::Text(data());
+        assert!(check(&mut unsigned, &known_users).is_err());
+
+        // we can't check anything without their key
+        let mut msg = identity.sign(Message::Text(data()));
+        assert_eq!(check(&mut msg, &KnownUsers::new()), Ok(()));
+        assert!(!msg.chat_data().unwrap().verified);
+    }
+}
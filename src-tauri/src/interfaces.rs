@@ -0,0 +1,107 @@
+use std::net::Ipv4Addr;
+use serde::Serialize;
+use ts_rs::TS;
+use tauri::State;
+
+use crate::AppState;
+
+// A local IPv4 interface we can send broadcasts out of
+#[derive(TS, Serialize, Clone, Debug)]
+#[ts(export)]
+#[ts(export_to="../src/lib/bindings/")]
+pub struct NetInterface {
+    pub name: String,
+    pub ip: String,
+    pub netmask: String,
+    pub broadcast: String,
+    pub enabled: bool,
+}
+
+pub struct LocalInterface {
+    pub name: String,
+    pub ip: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+    pub broadcast: Ipv4Addr,
+}
+
+// All non-loopback IPv4 interfaces on this machine, whether or not the user
+// has excluded them
+pub fn get_local_interfaces() -> Vec<LocalInterface> {
+    let interfaces = match if_addrs::get_if_addrs() {
+        Ok(interfaces) => interfaces,
+        Err(e) => {
+            log::error!("Error listing network interfaces: {e}");
+            return Vec::new();
+        },
+    };
+
+    interfaces
+        .into_iter()
+        .filter(|iface| !iface.is_loopback())
+        .filter_map(|iface| match iface.addr {
+            if_addrs::IfAddr::V4(v4) => Some(LocalInterface {
+                name: iface.name,
+                ip: v4.ip,
+                netmask: v4.netmask,
+                // not every platform reports the broadcast address, but it can
+                // always be worked out from the netmask
+                broadcast: v4.broadcast.unwrap_or_else(|| {
+                    Ipv4Addr::from(u32::from(v4.ip) | !u32::from(v4.netmask))
+                }),
+            }),
+            if_addrs::IfAddr::V6(_) => None,
+        })
+        .collect()
+}
+
+// Name and index of every interface with an IPv6 link-local address. The
+// index is what goes in the scope id of link-local socket addresses.
+pub fn get_link_local_v6_interfaces() -> Vec<(String, u32)> {
+    let interfaces = match if_addrs::get_if_addrs() {
+        Ok(interfaces) => interfaces,
+        Err(e) => {
+            log::error!("Error listing network interfaces: {e}");
+            return Vec::new();
+        },
+    };
+
+    let mut v6_interfaces: Vec<(String, u32)> = interfaces
+        .into_iter()
+        .filter(|iface| !iface.is_loopback() && iface.is_link_local())
+        .filter(|iface| matches!(iface.addr, if_addrs::IfAddr::V6(_)))
+        .filter_map(|iface| Some((iface.name, iface.index?)))
+        .collect();
+    v6_interfaces.dedup();
+    v6_interfaces
+}
+
+#[tauri::command]
+pub fn cmd_list_interfaces(state: State<AppState>) -> Vec<NetInterface> {
+    let excluded = state.connection.excluded_interfaces();
+    get_local_interfaces()
+        .into_iter()
+        .map(|iface| NetInterface {
+            enabled: !excluded.contains(&iface.name),
+            name: iface.name,
+            ip: iface.ip.to_string(),
+            netmask: iface.netmask.to_string(),
+            broadcast: iface.broadcast.to_string(),
+        })
+        .collect()
+}
+
+// Takes the names of the interfaces that should be used. Everything else
+// we can currently see gets excluded, while interfaces that show up later
+// (e.g. plugging in ethernet) are used by default.
+#[tauri::command]
+pub fn cmd_set_interfaces(enabled: Vec<String>, state: State<AppState>) -> Vec<NetInterface> {
+    let excluded = get_local_interfaces()
+        .into_iter()
+        .map(|iface| iface.name)
+        .filter(|name| !enabled.contains(name))
+        .collect();
+    log::info!("Excluding interfaces from broadcasts: {excluded:?}");
+    state.connection.set_excluded_interfaces(excluded);
+
+    cmd_list_interfaces(state)
+}
@@ -3,23 +3,49 @@
 
 use std::sync::{Arc, Mutex};
 
-use message::{Message, MessageData};
+use message::Message;
+use files::Files;
+use identity::Identity;
+use groups::Groups;
 use profile::Profile;
 use network::ConnectionState;
-use utilities::{gen_rand_id, get_curr_time, KnownUsers};
+use rooms::Rooms;
+use settings::Settings;
+use utilities::KnownUsers;
 use tauri::{Manager, State};
 
 mod message;
+mod codec;
 mod profile;
 mod network;
+mod peer;
+mod noise;
+mod identity;
+mod trust;
+mod interfaces;
+mod discovery;
+mod files;
+mod audio;
+mod groups;
+mod rooms;
+mod mdns;
+mod settings;
 mod utilities;
 
 pub struct AppState {
+    // of the room we are in, the others are kept in rooms
     pub msg_history: Arc<Mutex<Vec<Message>>>,
+    pub rooms: Arc<Mutex<Rooms>>,
+    pub groups: Arc<Mutex<Groups>>,
     pub profile: Arc<Mutex<Profile>>,
+    pub identity: Arc<Mutex<Identity>>,
 
     pub known_users: Arc<Mutex<KnownUsers>>,
 
+    pub settings: Arc<Mutex<Settings>>,
+
+    pub files: Arc<Mutex<Files>>,
+
     pub connection: ConnectionState,
 }
 
@@ -36,17 +62,64 @@ fn main() {
             profile::cmd_personalize_new_profile,
             network::cmd_send_text,
             network::cmd_send_img,
+            network::cmd_send_direct_text,
+            network::cmd_send_direct_img,
+            network::cmd_get_message_limits,
+            network::cmd_set_message_limits,
+            network::cmd_get_heartbeat_config,
+            network::cmd_set_heartbeat_config,
+            network::cmd_connect_peer,
+            network::cmd_list_static_peers,
+            network::cmd_remove_static_peer,
+            interfaces::cmd_list_interfaces,
+            interfaces::cmd_set_interfaces,
+            discovery::cmd_get_discovery_config,
+            discovery::cmd_set_discovery_config,
+            files::cmd_offer_file,
+            files::cmd_accept_file,
+            files::cmd_decline_file,
+            files::cmd_save_file,
+            rooms::cmd_list_rooms,
+            rooms::cmd_list_nearby_rooms,
+            rooms::cmd_join_room,
+            rooms::cmd_leave_room,
+            rooms::cmd_set_room_capacity,
+            rooms::cmd_set_room_passphrase,
+            noise::cmd_get_encryption_required,
+            noise::cmd_set_encryption_required,
+            groups::cmd_create_group,
+            groups::cmd_set_group_members,
+            groups::cmd_leave_group,
+            groups::cmd_list_groups,
+            groups::cmd_send_group_text,
+            groups::cmd_send_group_img,
+            audio::cmd_send_audio,
+            audio::cmd_decode_audio,
             utilities::cmd_get_known_users,
+            trust::cmd_set_user_verified,
         ])
         .on_window_event(handle_window_event)
         .manage(AppState {
             msg_history: Arc::new(Mutex::new(Vec::new())),
+            rooms: Arc::new(Mutex::new(Rooms::new())),
+            groups: Arc::new(Mutex::new(Groups::new())),
             profile: Arc::new(Mutex::new(Profile::new("unnamed".to_owned()))),
+            identity: Arc::new(Mutex::new(Identity::generate())),
             known_users: Arc::new(Mutex::new(KnownUsers::new())),
+            settings: Arc::new(Mutex::new(Settings::default())),
+            files: Arc::new(Mutex::new(Files::new())),
             connection: ConnectionState::new(),
         })
+        .setup(|app| {
+            let state: State<AppState> = app.state();
+            *state.settings.lock().unwrap() = Settings::load(&app.handle());
+            let identity = Identity::load(&app.handle());
+            state.profile.lock().unwrap().public_key = identity.public_key();
+            *state.identity.lock().unwrap() = identity;
+            Ok(())
+        })
         .on_page_load(|window, _payload| {
-            network::run_background_threads(window);
+            network::run_network_task(window);
         })
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
@@ -56,17 +129,10 @@ fn handle_window_event(event: tauri::GlobalWindowEvent) {
     match event.event() {
         tauri::WindowEvent::Destroyed => {
             let state: State<AppState> = event.window().state();
-            let profile = state.profile.lock().unwrap();
-
-            let goodbye_msg = Message::Goodbye(MessageData::new(
-                profile.name.clone(),
-                profile.uid,
-                gen_rand_id(),
-                get_curr_time(),
-                profile.pic.clone(),
-            ));
+            let goodbye_msg = state.profile.lock().unwrap().make_goodbye_msg(&state.identity.lock().unwrap());
 
-            network::send_msgs_to_all_peers(vec![goodbye_msg], event.window());
+            state.connection.shutdown(goodbye_msg);
+            state.connection.mdns.stop();
         },
         _ => {},
     }
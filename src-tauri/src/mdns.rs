@@ -0,0 +1,151 @@
+// Advertises and browses for an _ectochat._tcp DNS-SD service over mDNS.
+// This is a second way of finding peers, for networks that block our
+// broadcast port, and it lets standard service browsers see ectochat hosts.
+
+use std::{sync::Mutex, collections::HashMap, net::{IpAddr, SocketAddr}};
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo, Receiver};
+
+use crate::message::{BroadcastData, PROTOCOL_VERSION};
+use crate::profile::Profile;
+use crate::rooms::DEFAULT_ROOM;
+
+pub const SERVICE_TYPE: &str = "_ectochat._tcp.local.";
+
+// TXT record keys
+const TXT_UID: &str = "uid";
+const TXT_NAME: &str = "name";
+const TXT_VERSION: &str = "version";
+const TXT_ROOM: &str = "room";
+const TXT_LOCKED: &str = "locked";
+
+pub struct Mdns {
+    daemon: Option<ServiceDaemon>, // None if mDNS couldn't be started
+    registered: Mutex<Option<String>>, // full name of our service once advertised
+    browser: Mutex<Option<Receiver<ServiceEvent>>>,
+}
+
+impl Mdns {
+    pub fn new() -> Mdns {
+        let daemon = match ServiceDaemon::new() {
+            Ok(daemon) => Some(daemon),
+            Err(e) => {
+                log::warn!("Could not start mDNS daemon, mDNS discovery disabled: {e}");
+                None
+            },
+        };
+
+        Mdns {
+            daemon,
+            registered: Mutex::new(None),
+            browser: Mutex::new(None),
+        }
+    }
+
+    // Makes sure we are advertised and browsing, and returns every peer
+    // that has been resolved since the last call
+    pub fn poll(&self, profile: &Profile, port: u16, room: &str, locked: bool) -> Vec<(BroadcastData, SocketAddr)> {
+        let daemon = match &self.daemon {
+            Some(daemon) => daemon,
+            None => return Vec::new(),
+        };
+
+        {
+            let mut registered = self.registered.lock().unwrap();
+            if registered.is_none() {
+                match make_service_info(profile, port, room, locked) {
+                    Ok(info) => {
+                        let fullname = info.get_fullname().to_owned();
+                        match daemon.register(info) {
+                            Ok(()) => {
+                                log::info!("Advertising {fullname} over mDNS");
+                                *registered = Some(fullname);
+                            },
+                            Err(e) => log::error!("Error registering mDNS service: {e}"),
+                        }
+                    },
+                    Err(e) => log::error!("Error creating mDNS service info: {e}"),
+                }
+            }
+        }
+
+        let mut browser = self.browser.lock().unwrap();
+        if browser.is_none() {
+            match daemon.browse(SERVICE_TYPE) {
+                Ok(receiver) => *browser = Some(receiver),
+                Err(e) => {
+                    log::error!("Error browsing for {SERVICE_TYPE}: {e}");
+                    return Vec::new();
+                },
+            }
+        }
+
+        let mut peers = Vec::new();
+        if let Some(receiver) = browser.as_ref() {
+            while let Ok(event) = receiver.try_recv() {
+                if let ServiceEvent::ServiceResolved(info) = event {
+                    match parse_service_info(&info) {
+                        Some(peer) => peers.push(peer),
+                        None => log::debug!("Ignoring mDNS service we couldn't parse: {}", info.get_fullname()),
+                    }
+                }
+            }
+        }
+        peers
+    }
+
+    // Stops advertising and browsing, e.g. when mDNS is turned off or we are
+    // shutting down. poll will start them back up again.
+    pub fn stop(&self) {
+        let daemon = match &self.daemon {
+            Some(daemon) => daemon,
+            None => return,
+        };
+
+        if let Some(fullname) = self.registered.lock().unwrap().take() {
+            if let Err(e) = daemon.unregister(&fullname) {
+                log::error!("Error unregistering {fullname}: {e}");
+            }
+        }
+        if self.browser.lock().unwrap().take().is_some() {
+            let _ = daemon.stop_browse(SERVICE_TYPE);
+        }
+    }
+}
+
+fn make_service_info(profile: &Profile, port: u16, room: &str, locked: bool) -> mdns_sd::Result<ServiceInfo> {
+    // Instance names have to be unique on the network, and names alone aren't
+    let instance_name = format!("{}-{:08x}", profile.name, profile.uid);
+    let host_name = format!("ectochat-{:08x}.local.", profile.uid);
+
+    let properties = HashMap::from([
+        (TXT_UID.to_owned(), profile.uid.to_string()),
+        (TXT_NAME.to_owned(), profile.name.clone()),
+        (TXT_VERSION.to_owned(), PROTOCOL_VERSION.to_string()),
+        (TXT_ROOM.to_owned(), room.to_owned()),
+        (TXT_LOCKED.to_owned(), (locked as u8).to_string()),
+    ]);
+
+    // No addresses given here, the daemon fills in (and keeps up to date)
+    // the addresses of every interface
+    Ok(ServiceInfo::new(SERVICE_TYPE, &instance_name, &host_name, (), port, properties)?.enable_addr_auto())
+}
+
+fn parse_service_info(info: &ServiceInfo) -> Option<(BroadcastData, SocketAddr)> {
+    let uid = info.get_property_val_str(TXT_UID)?.parse().ok()?;
+    let protocol_version = info.get_property_val_str(TXT_VERSION)?.parse().ok()?;
+    let room = info.get_property_val_str(TXT_ROOM).unwrap_or(DEFAULT_ROOM).to_owned();
+    let locked = info.get_property_val_str(TXT_LOCKED) == Some("1");
+    let port = info.get_port();
+
+    // IPv6 link-local addresses are no good to us without a scope id, which
+    // mDNS doesn't give us, so prefer IPv4
+    let addrs = info.get_addresses();
+    let ip = addrs.iter().find(|ip| ip.is_ipv4())
+        .or_else(|| addrs.iter().find(|ip| match ip {
+            IpAddr::V6(v6) => (v6.segments()[0] & 0xffc0) != 0xfe80,
+            IpAddr::V4(_) => false,
+        }))?;
+
+    // How many are in their room changes too often to keep re-advertising
+    Some((BroadcastData { uid, port, protocol_version, room, members: 0, locked }, SocketAddr::new(*ip, port)))
+}
@@ -1,4 +1,5 @@
 use std::io::Write;
+use std::fmt;
 use serde::{Serialize, Deserialize};
 use ts_rs::TS;
 use std::io::prelude::*;
@@ -6,22 +7,197 @@ use flate2::Compression;
 use flate2::write::GzEncoder;
 use flate2::read::GzDecoder;
 
+use crate::codec;
+use crate::rooms::DEFAULT_ROOM;
+
 pub const HEADER_LEN: usize = 8; // number of bytes we store the whole msg len in (little endian)
 
+// Sent in our Hello so the other side knows what it can use with us. Builds
+// from before this existed send neither, and get treated as version 0.
+// Version 2 starts every connection with a Noise handshake (see noise.rs).
+pub const PROTOCOL_VERSION: u16 = 2;
+pub const CAP_BINARY: &str = "binary";
+pub const CAP_HEARTBEAT: &str = "heartbeat";
+pub const CAP_RESUME: &str = "resume";
+pub const CAP_CHUNKS: &str = "chunks";
+pub const CAP_FILES: &str = "files";
+pub const CAP_AUDIO: &str = "audio";
+pub const CAP_DIRECT: &str = "direct";
+pub const CAP_GROUPS: &str = "groups";
+pub const CAP_ROOMS: &str = "rooms";
+
+// Messages in the media lane whose frame is bigger than this are sent as
+// chunks of this size, so everything else can be sent in between
+pub const CHUNK_LEN: usize = 16 * 1024;
+
+// Files are sent in parts of at most this many bytes. Small enough that a
+// part never needs chunking, so the receiver can write each one as it arrives.
+pub const FILE_PART_LEN: usize = 12 * 1024;
+
+// How messages are serialized on a particular connection. Everything starts
+// out as Json, since that is all older builds understand.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum WireFormat {
+    Json,
+    Binary,
+}
+
+impl WireFormat {
+    pub fn negotiate(hello: &HelloData) -> Self {
+        if hello.protocol_version >= 1 && hello.has_capability(CAP_BINARY) {
+            WireFormat::Binary
+        } else {
+            WireFormat::Json
+        }
+    }
+}
+
+const KIB: u64 = 1024;
+const MIB: u64 = 1024 * KIB;
+
+// Maximum sizes of a single message on the wire (compressed, not including
+// the header) and after inflating it
+#[derive(TS, Serialize, Deserialize, Clone, Copy, Debug)]
+#[ts(export)]
+#[ts(export_to="../src/lib/bindings/")]
+pub struct SizeLimit {
+    pub compressed: u64,
+    pub decompressed: u64,
+}
+
+impl SizeLimit {
+    const fn new(compressed: u64, decompressed: u64) -> Self {
+        SizeLimit { compressed, decompressed }
+    }
+}
+
+// Per message type size limits. Everything a peer sends is checked against
+// these before we allocate for it or hand it to serde.
 #[derive(TS, Serialize, Deserialize, Clone, Debug)]
 #[ts(export)]
 #[ts(export_to="../src/lib/bindings/")]
+pub struct MessageLimits {
+    pub broadcast: SizeLimit,
+    // Hello, Goodbye and Dropped all carry the profile picture
+    pub hello: SizeLimit,
+    pub goodbye: SizeLimit,
+    pub dropped: SizeLimit,
+    // Text and Image also cover their Direct and Group versions
+    pub text: SizeLimit,
+    pub image: SizeLimit,
+    pub ack: SizeLimit,
+    // Ping and Pong
+    pub heartbeat: SizeLimit,
+    pub sync: SizeLimit,
+    pub chunk: SizeLimit,
+    pub file: SizeLimit,
+    pub file_request: SizeLimit,
+    pub file_part: SizeLimit,
+    pub audio: SizeLimit,
+    // GroupCreate and GroupUpdate
+    pub group: SizeLimit,
+    pub disconnect: SizeLimit,
+    pub room_share: SizeLimit,
+}
+
+impl Default for MessageLimits {
+    fn default() -> Self {
+        // pictures are sent as json arrays of decimal numbers, so each byte of
+        // a 96x96 profile pic or 384x192 drawing can take up to 4 characters
+        MessageLimits {
+            broadcast: SizeLimit::new(KIB, KIB),
+            hello: SizeLimit::new(256 * KIB, 256 * KIB),
+            goodbye: SizeLimit::new(256 * KIB, 256 * KIB),
+            dropped: SizeLimit::new(256 * KIB, 256 * KIB),
+            text: SizeLimit::new(64 * KIB, 64 * KIB),
+            image: SizeLimit::new(2 * MIB, 2 * MIB),
+            ack: SizeLimit::new(KIB, KIB),
+            heartbeat: SizeLimit::new(KIB, KIB),
+            sync: SizeLimit::new(KIB, KIB),
+            chunk: SizeLimit::new(CHUNK_LEN as u64 + KIB, CHUNK_LEN as u64 + KIB),
+            file: SizeLimit::new(4 * KIB, 4 * KIB),
+            file_request: SizeLimit::new(KIB, KIB),
+            file_part: SizeLimit::new(FILE_PART_LEN as u64 + KIB, FILE_PART_LEN as u64 + KIB),
+            // the longest clip at our bitrate is about 90 KiB of opus, which
+            // can take up to 4 characters a byte as json
+            audio: SizeLimit::new(512 * KIB, 512 * KIB),
+            group: SizeLimit::new(4 * KIB, 4 * KIB),
+            disconnect: SizeLimit::new(KIB, KIB),
+            room_share: SizeLimit::new(KIB, KIB),
+        }
+    }
+}
+
+impl MessageLimits {
+    pub fn for_msg(&self, msg: &Message) -> SizeLimit {
+        match msg {
+            Message::Broadcast(_) => self.broadcast,
+            Message::Hello(_) => self.hello,
+            Message::Goodbye(_) => self.goodbye,
+            Message::Dropped(_) => self.dropped,
+            Message::Text(_) | Message::DirectText(_) | Message::GroupText(_) => self.text,
+            Message::Image(_) | Message::DirectImage(_) | Message::GroupImage(_) => self.image,
+            Message::Ack { uid:_, mid:_ } => self.ack,
+            Message::Ping(_) | Message::Pong(_) => self.heartbeat,
+            Message::Sync { seen:_ } => self.sync,
+            Message::Chunk(_) => self.chunk,
+            Message::File(_) => self.file,
+            Message::FileRequest { mid:_, offset:_ } => self.file_request,
+            Message::FilePart { mid:_, offset:_, data:_ } => self.file_part,
+            Message::Audio(_) => self.audio,
+            Message::GroupCreate(_) | Message::GroupUpdate(_) => self.group,
+            Message::Disconnect { reason:_ } => self.disconnect,
+            Message::RoomShare(_) => self.room_share,
+        }
+    }
+
+    fn all(&self) -> [SizeLimit; 16] {
+        [
+            self.broadcast, self.hello, self.goodbye, self.dropped, self.text,
+            self.image, self.ack, self.heartbeat, self.sync, self.chunk,
+            self.file, self.file_request, self.file_part, self.audio, self.group,
+            self.disconnect,
+        ]
+    }
+
+    // We can't know the type of a message until it has been decoded, so these
+    // are the bounds used before then
+    pub fn max_compressed(&self) -> u64 {
+        self.all().iter().map(|l| l.compressed).max().unwrap_or(0)
+    }
+
+    pub fn max_decompressed(&self) -> u64 {
+        self.all().iter().map(|l| l.decompressed).max().unwrap_or(0)
+    }
+
+    pub fn check_frame_len(&self, msg_len: u64) -> Result<(), DecodeError> {
+        let max = self.max_compressed();
+        if msg_len > max {
+            Err(DecodeError::FrameTooLarge { len: msg_len, max })
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[derive(TS, Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[ts(export)]
+#[ts(export_to="../src/lib/bindings/")]
 pub enum Message {
     // Broadcast messages sent to find other hosts
-    // Send out UID in broadcast message
+    // Send out UID and the port we are listening for tcp connections on
+    // in broadcast message
     // It is the responsibility of the host with greater 
     // UID to initiate the TCP connection
-    Broadcast(u32),
+    // Builds from before this carried anything but the uid sent just that, and
+    // still do (see legacy_broadcast), so either is accepted
+    #[serde(deserialize_with = "deserialize_broadcast")]
+    Broadcast(BroadcastData),
 
     // Message sent in response to broadcast, over tcp,
     // to establish TCP connection
     // Payload is the profile picture
-    Hello(MessageData),
+    Hello(Box<HelloData>),
 
     // Message sent when app is closed gracefully
     Goodbye(MessageData),
@@ -34,32 +210,147 @@ pub enum Message {
     Text(MessageData),
     Image(MessageData),
     Ack{ uid: u32, mid: u32 },
+
+    // Sent periodically to peers that support heartbeats, so we notice when
+    // they vanish without a Goodbye. Pong echoes back the nonce of the Ping.
+    // Never shown in the frontend.
+    Ping(u32),
+    Pong(u32),
+
+    // Sent after Hello to peers that support resuming. seen is the mids of
+    // the last few Text/Image messages we have, and the peer answers by
+    // resending everything in its history after them. Never shown in the frontend.
+    Sync{ seen: Vec<u32> },
+
+    // A piece of a message too big to send in one go, so that other messages
+    // can be sent in between. Only sent to peers that support chunks, and
+    // put back together before anything else sees it.
+    Chunk(ChunkData),
+
+    // Offer of a file, shown in the chat like Text. Nothing else is sent
+    // until someone accepts it.
+    File(Box<FileData>),
+    // Sent only to whoever offered the file, asking for everything from
+    // offset on. Also sent after a reconnect to pick up where we left off.
+    FileRequest{ mid: u32, offset: u64 },
+    // The bytes of an offered file starting at offset, in answer to a
+    // FileRequest. Never shown in the frontend.
+    FilePart{ mid: u32, offset: u64, data: Vec<u8> },
+
+    // Voice message, shown in the chat like Image
+    Audio(AudioData),
+
+    // Text and Image for one peer only. Only ever sent to them and back to
+    // ourselves, so that it shows up in our own chat.
+    DirectText(DirectData),
+    DirectImage(DirectData),
+
+    // Sent to every member of a group when it is made, and whenever its
+    // members change. Both carry the whole group, and are only accepted from
+    // its members. Never shown in the frontend.
+    GroupCreate(Box<GroupData>),
+    GroupUpdate(Box<GroupData>),
+    // Text and Image only sent to the members of a group
+    GroupText(GroupMsgData),
+    GroupImage(GroupMsgData),
+
+    // Answer to a Hello from a peer we won't talk to, e.g. because our room
+    // is full, sent just before we close the connection. Never shown in the
+    // frontend.
+    Disconnect{ reason: String },
+
+    // Each side's half of the passphrase exchange for a locked room, sent
+    // right after the handshake, before either Hello. See rooms.rs. Never
+    // shown in the frontend.
+    RoomShare(Vec<u8>),
+}
+
+// Which lane a message is sent in. A peer's writer always sends from the
+// first lane that has something waiting.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Priority {
+    Control,
+    Text,
+    Media,
 }
 
 impl Message {
     // prepends with 8 bytes (little endian) of size of entire packet
     // so it can be picked out from the tcp stream
-    pub fn to_network(&self) -> Vec<u8> {
-        let mut e = GzEncoder::new(Vec::new(), Compression::default());
-        if let Err(err) = e.write_all(serde_json::to_string(&self).unwrap().as_bytes()) {
-            log::error!("{err}");
-        }
-        let message_bytes = e.finish().unwrap();
+    pub fn to_network(&self, format: WireFormat) -> Vec<u8> {
+        let message_bytes = match format {
+            WireFormat::Json => gzip_json(self),
+            WireFormat::Binary => codec::encode(self),
+        };
         
-        let message_len = message_bytes.len() as u64;
+        frame(message_bytes)
+    }
+
+    // The only broadcast builds from before BroadcastData understand. They
+    // unwrap whatever they get on the discovery port, and only read the first
+    // 100 bytes of it, so this goes at the start of every datagram we send,
+    // with the full broadcast after it.
+    pub fn legacy_broadcast(uid: u32) -> Vec<u8> {
+        #[derive(Serialize)]
+        enum LegacyMessage {
+            Broadcast(u32),
+        }
 
-        [message_len.to_le_bytes().to_vec(), message_bytes].concat()
+        frame(gzip_json(&LegacyMessage::Broadcast(uid)))
+    }
+
+    // How many bytes the frame at the start of buf takes up, header included.
+    // Only meaningful once from_network has accepted it.
+    pub fn frame_len(buf: &[u8]) -> usize {
+        let mut header = [0u8; HEADER_LEN];
+        header.copy_from_slice(&buf[..HEADER_LEN]);
+        HEADER_LEN + u64::from_le_bytes(header) as usize
     }
 
     // buf should still include the 8 bytes that has the size of the whole
-    // msg, because this will strip it off, and it should not go further
-    // than the end of the message
-    pub fn from_network(buf: &[u8]) -> Self {
-        let buf = &buf[HEADER_LEN..]; // strip off 8 byte header
-        let mut d = GzDecoder::new(buf);
-        let mut s = String::new();
-        d.read_to_string(&mut s).unwrap();
-        serde_json::from_str(&s).unwrap()
+    // msg, because this will strip it off. Anything after the end of the
+    // message is ignored. Peers are untrusted, so every failure is reported
+    // back to the caller instead of panicking. Either wire format is accepted,
+    // regardless of what was negotiated.
+    pub fn from_network(buf: &[u8], limits: &MessageLimits) -> Result<Self, DecodeError> {
+        if buf.len() < HEADER_LEN {
+            return Err(DecodeError::TruncatedHeader { len: buf.len() });
+        }
+
+        let mut header = [0u8; HEADER_LEN];
+        header.copy_from_slice(&buf[..HEADER_LEN]);
+        let msg_len = u64::from_le_bytes(header);
+        limits.check_frame_len(msg_len)?;
+
+        let body = &buf[HEADER_LEN..]; // strip off 8 byte header
+        if (body.len() as u64) < msg_len {
+            return Err(DecodeError::TruncatedBody { expected: msg_len, actual: body.len() });
+        }
+        let body = &body[..msg_len as usize];
+
+        let max_decompressed = limits.max_decompressed();
+        let (msg, decompressed_len) = if body.first() == Some(&codec::MAGIC) {
+            codec::decode(body, max_decompressed)?
+        } else {
+            let bytes = inflate(body, max_decompressed)?;
+            let decompressed_len = bytes.len() as u64;
+
+            let s = String::from_utf8(bytes).map_err(DecodeError::InvalidUtf8)?;
+            let msg: Message = serde_json::from_str(&s).map_err(DecodeError::from_json_err)?;
+            (msg, decompressed_len)
+        };
+
+        let limit = limits.for_msg(&msg);
+        if msg_len > limit.compressed || decompressed_len > limit.decompressed {
+            return Err(DecodeError::MessageTooLarge {
+                msg_type: msg.get_type_str().to_owned(),
+                compressed: msg_len,
+                decompressed: decompressed_len,
+                limit,
+            });
+        }
+
+        Ok(msg)
     }
 
     pub fn get_type_str(&self) -> &str {
@@ -71,10 +362,181 @@ impl Message {
             Self::Dropped(_) => "Dropped",
             Self::Image(_) => "Image",
             Self::Text(_) => "Text",
+            Self::Ping(_) => "Ping",
+            Self::Pong(_) => "Pong",
+            Self::Sync { seen:_ } => "Sync",
+            Self::Chunk(_) => "Chunk",
+            Self::File(_) => "File",
+            Self::FileRequest { mid:_, offset:_ } => "FileRequest",
+            Self::FilePart { mid:_, offset:_, data:_ } => "FilePart",
+            Self::Audio(_) => "Audio",
+            Self::DirectText(_) => "DirectText",
+            Self::DirectImage(_) => "DirectImage",
+            Self::GroupCreate(_) => "GroupCreate",
+            Self::GroupUpdate(_) => "GroupUpdate",
+            Self::GroupText(_) => "GroupText",
+            Self::GroupImage(_) => "GroupImage",
+            Self::Disconnect { reason:_ } => "Disconnect",
+            Self::RoomShare(_) => "RoomShare",
+        }
+    }
+
+    pub fn priority(&self) -> Priority {
+        match self {
+            Self::Text(_) | Self::DirectText(_) | Self::GroupText(_) | Self::Dropped(_) | Self::File(_) => Priority::Text,
+            Self::GroupCreate(_) | Self::GroupUpdate(_) => Priority::Text, // so they are ahead of any GroupImage
+            Self::Image(_) | Self::DirectImage(_) | Self::GroupImage(_) | Self::Audio(_) | Self::FilePart { mid:_, offset:_, data:_ } => Priority::Media,
+            _ => Priority::Control,
+        }
+    }
+
+    // What a peer has to have put in its Hello for us to send it this.
+    // Older builds can't decode anything newer than they are, and cut off
+    // whoever sends it to them.
+    pub fn required_capability(&self) -> Option<&'static str> {
+        match self {
+            Self::Ping(_) | Self::Pong(_) => Some(CAP_HEARTBEAT),
+            Self::Sync { seen:_ } => Some(CAP_RESUME),
+            Self::Chunk(_) => Some(CAP_CHUNKS),
+            Self::File(_) | Self::FileRequest { mid:_, offset:_ } | Self::FilePart { mid:_, offset:_, data:_ } => {
+                Some(CAP_FILES)
+            },
+            Self::Audio(_) => Some(CAP_AUDIO),
+            Self::DirectText(_) | Self::DirectImage(_) => Some(CAP_DIRECT),
+            Self::GroupCreate(_) | Self::GroupUpdate(_) | Self::GroupText(_) | Self::GroupImage(_) => Some(CAP_GROUPS),
+            Self::Disconnect { reason:_ } => Some(CAP_ROOMS),
+            _ => None,
+        }
+    }
+
+    // The sender and mid of messages that are shown in the chat, which are
+    // the ones that get acked, deduplicated and resent after a drop
+    pub fn chat_data(&self) -> Option<&MessageData> {
+        match self {
+            Self::Text(data) | Self::Image(data) => Some(data),
+            Self::File(file) => Some(&file.data),
+            Self::Audio(audio) => Some(&audio.data),
+            Self::DirectText(direct) | Self::DirectImage(direct) => Some(&direct.data),
+            Self::GroupText(group) | Self::GroupImage(group) => Some(&group.data),
+            _ => None,
+        }
+    }
+
+    // Which group a message is for. None if it isn't for a group.
+    pub fn group(&self) -> Option<u32> {
+        match self {
+            Self::GroupText(group) | Self::GroupImage(group) => Some(group.gid),
+            _ => None,
+        }
+    }
+
+    // Who a private message is for. None if it is for everyone.
+    pub fn recipient(&self) -> Option<u32> {
+        match self {
+            Self::DirectText(direct) | Self::DirectImage(direct) => Some(direct.to),
+            _ => None,
+        }
+    }
+}
+
+fn gzip_json(value: &impl Serialize) -> Vec<u8> {
+    let mut e = GzEncoder::new(Vec::new(), Compression::default());
+    if let Err(err) = e.write_all(serde_json::to_string(value).unwrap().as_bytes()) {
+        log::error!("{err}");
+    }
+    e.finish().unwrap()
+}
+
+// prepends with 8 bytes (little endian) of the size of the message
+fn frame(message_bytes: Vec<u8>) -> Vec<u8> {
+    let message_len = message_bytes.len() as u64;
+
+    [message_len.to_le_bytes().to_vec(), message_bytes].concat()
+}
+
+// Read at most one byte past the limit so we can tell a message that is
+// exactly at the limit from one that would keep inflating forever
+pub fn inflate(buf: &[u8], max_decompressed: u64) -> Result<Vec<u8>, DecodeError> {
+    let mut d = GzDecoder::new(buf).take(max_decompressed.saturating_add(1));
+    let mut bytes = Vec::new();
+    d.read_to_end(&mut bytes).map_err(DecodeError::BadGzip)?;
+    if bytes.len() as u64 > max_decompressed {
+        return Err(DecodeError::DecompressedTooLarge { max: max_decompressed });
+    }
+    Ok(bytes)
+}
+
+#[derive(Debug)]
+pub enum DecodeError {
+    // Not even enough bytes for the 8 byte length prefix
+    TruncatedHeader { len: usize },
+    // Length prefix promised more bytes than were handed to us
+    TruncatedBody { expected: u64, actual: usize },
+    BadGzip(std::io::Error),
+    InvalidUtf8(std::string::FromUtf8Error),
+    // Binary frame from a newer build than we understand
+    UnsupportedVersion(u8),
+    // Well formed, but a Message variant we don't know about (e.g. from a newer build)
+    UnknownVariant(String),
+    // Anything else we didn't like: bad json, missing fields, wrong types
+    SchemaMismatch(String),
+    // Length prefix is bigger than anything we are willing to accept
+    FrameTooLarge { len: u64, max: u64 },
+    // Gzip data would inflate past anything we are willing to accept
+    DecompressedTooLarge { max: u64 },
+    // Decoded fine, but bigger than allowed for its type
+    MessageTooLarge { msg_type: String, compressed: u64, decompressed: u64, limit: SizeLimit },
+    // Chunk that doesn't fit with the others in its transfer
+    BadChunk(String),
+    // Handshake that went wrong, or a record that didn't decrypt
+    Encryption(String),
+}
+
+impl DecodeError {
+    fn from_json_err(err: serde_json::Error) -> Self {
+        // serde_json doesn't expose the unknown variant name directly, so
+        // pull it out of the message, which looks like
+        // "unknown variant `Foo`, expected one of ..."
+        let err_str = err.to_string();
+        if err.is_data() && err_str.starts_with("unknown variant") {
+            if let Some(name) = err_str.split('`').nth(1) {
+                return DecodeError::UnknownVariant(name.to_owned());
+            }
         }
+        DecodeError::SchemaMismatch(err_str)
     }
 }
 
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TruncatedHeader { len } => 
+                write!(f, "truncated header: only {len} of {HEADER_LEN} bytes"),
+            Self::TruncatedBody { expected, actual } => 
+                write!(f, "truncated body: expected {expected} bytes, got {actual}"),
+            Self::BadGzip(e) => write!(f, "bad gzip data: {e}"),
+            Self::InvalidUtf8(e) => write!(f, "invalid utf-8: {e}"),
+            Self::UnsupportedVersion(v) => write!(f, "unsupported binary protocol version {v}"),
+            Self::UnknownVariant(name) => write!(f, "unknown message variant {name}"),
+            Self::SchemaMismatch(e) => write!(f, "schema mismatch: {e}"),
+            Self::FrameTooLarge { len, max } => 
+                write!(f, "frame of {len} bytes exceeds the {max} byte limit"),
+            Self::DecompressedTooLarge { max } => 
+                write!(f, "message inflates past the {max} byte limit"),
+            Self::MessageTooLarge { msg_type, compressed, decompressed, limit } => 
+                write!(
+                    f, 
+                    "{msg_type} message of {compressed} bytes ({decompressed} inflated) exceeds the {} ({}) byte limit",
+                    limit.compressed, limit.decompressed
+                ),
+            Self::BadChunk(e) => write!(f, "bad chunk: {e}"),
+            Self::Encryption(e) => write!(f, "encryption error: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
 #[derive(TS, Serialize, Deserialize, Clone, Ord, PartialOrd, PartialEq, Eq, Debug)]
 #[ts(export)]
 #[ts(export_to="../src/lib/bindings/")]
@@ -84,10 +546,319 @@ pub struct MessageData {
     pub mid: u32,
     pub timestamp: u64,
     pub payload: Vec<u8>,
+    // Ed25519 signature by the sender's identity, see identity.rs. Empty on
+    // anything that isn't signed, and from older builds.
+    #[serde(default)]
+    pub signature: Vec<u8>,
+    // Set by whoever receives it once the signature checks out, for the
+    // frontend. Never taken from the wire.
+    #[serde(skip_deserializing)]
+    pub verified: bool,
 }
 
 impl MessageData {
     pub fn new(name: String, uid: u32, mid: u32, timestamp: u64, payload: Vec<u8> ) -> MessageData {
-        MessageData { name, uid, mid, timestamp, payload }
+        MessageData { name, uid, mid, timestamp, payload, signature: Vec::new(), verified: false }
+    }
+}
+
+#[derive(TS, Serialize, Deserialize, Clone, Ord, PartialOrd, PartialEq, Eq, Debug)]
+#[ts(export)]
+#[ts(export_to="../src/lib/bindings/")]
+pub struct BroadcastData {
+    pub uid: u32,
+    pub port: u16,
+    pub protocol_version: u16,
+    // Older builds don't send this, and are all in the default room
+    #[serde(default = "default_room")]
+    pub room: String,
+    // How many people are in their room, them included. 0 from older builds.
+    #[serde(default)]
+    pub members: u32,
+    // Whether their room needs a passphrase, see rooms.rs
+    #[serde(default)]
+    pub locked: bool,
+}
+
+impl BroadcastData {
+    pub fn new(uid: u32, port: u16, room: String, members: u32, locked: bool) -> BroadcastData {
+        BroadcastData { uid, port, protocol_version: PROTOCOL_VERSION, room, members, locked }
+    }
+
+    // From a build that only broadcasts its uid. Port 0 since it could be
+    // listening anywhere in the usual range.
+    fn legacy(uid: u32) -> BroadcastData {
+        BroadcastData { uid, port: 0, protocol_version: 0, room: default_room(), members: 0, locked: false }
+    }
+}
+
+fn deserialize_broadcast<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<BroadcastData, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum AnyBroadcast {
+        Legacy(u32),
+        Full(BroadcastData),
+    }
+
+    Ok(match AnyBroadcast::deserialize(deserializer)? {
+        AnyBroadcast::Legacy(uid) => BroadcastData::legacy(uid),
+        AnyBroadcast::Full(data) => data,
+    })
+}
+
+fn default_room() -> String {
+    DEFAULT_ROOM.to_owned()
+}
+
+// Hello is the only message that has to be understood by every build, so the
+// extra fields are flattened next to the MessageData ones. Older builds
+// ignore them, and we default them when talking to older builds.
+#[derive(TS, Serialize, Deserialize, Clone, Ord, PartialOrd, PartialEq, Eq, Debug)]
+#[ts(export)]
+#[ts(export_to="../src/lib/bindings/")]
+pub struct HelloData {
+    #[serde(flatten)]
+    pub data: MessageData,
+    #[serde(default)]
+    pub protocol_version: u16,
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+    // tcp port they accept connections on, so we can redial them after a
+    // drop even if they were the one who dialed us. 0 if unknown.
+    #[serde(default)]
+    pub listen_port: u16,
+    // which room they are in, see rooms.rs
+    #[serde(default = "default_room")]
+    pub room: String,
+    // Ed25519 public key of their identity, see identity.rs. Empty from older builds.
+    #[serde(default)]
+    pub public_key: Vec<u8>,
+    // Signature by that key over the connection's Noise handshake hash, to
+    // prove it's really theirs. Empty in plaintext and from older builds.
+    #[serde(default)]
+    pub handshake_sig: Vec<u8>,
+    // Proves they know the passphrase for their room if it's locked, empty
+    // otherwise. See rooms.rs.
+    #[serde(default)]
+    pub room_proof: Vec<u8>,
+}
+
+// Everything this build supports, as sent in our Hello
+pub fn capabilities() -> Vec<String> {
+    vec![
+        CAP_BINARY.to_owned(), CAP_HEARTBEAT.to_owned(), CAP_RESUME.to_owned(), CAP_CHUNKS.to_owned(),
+        CAP_FILES.to_owned(), CAP_AUDIO.to_owned(), CAP_DIRECT.to_owned(),
+        CAP_GROUPS.to_owned(), CAP_ROOMS.to_owned(),
+    ]
+}
+
+impl HelloData {
+    pub fn new(
+        data: MessageData,
+        listen_port: u16,
+        room: String,
+        public_key: Vec<u8>,
+        handshake_sig: Vec<u8>,
+        room_proof: Vec<u8>,
+    ) -> HelloData {
+        HelloData {
+            data,
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: capabilities(),
+            listen_port,
+            room,
+            public_key,
+            handshake_sig,
+            room_proof,
+        }
+    }
+
+    pub fn has_capability(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c == capability)
+    }
+}
+
+// The frame of the whole message (header included) is split into payloads,
+// numbered from 0. The transfer is done once the chunk with last set arrives,
+// which also carries the sha256 of the whole frame.
+#[derive(TS, Serialize, Deserialize, Clone, Ord, PartialOrd, PartialEq, Eq, Debug)]
+#[ts(export)]
+#[ts(export_to="../src/lib/bindings/")]
+pub struct ChunkData {
+    pub transfer_id: u32,
+    pub seq: u32,
+    pub total_len: u64, // of the whole frame, the same in every chunk
+    pub last: bool,
+    pub hash: Vec<u8>, // empty until the last chunk
+    pub payload: Vec<u8>,
+}
+
+
+// The payload of data is empty, the file itself is only sent to whoever
+// accepts it. hash is the sha256 of the whole file.
+#[derive(TS, Serialize, Deserialize, Clone, Ord, PartialOrd, PartialEq, Eq, Debug)]
+#[ts(export)]
+#[ts(export_to="../src/lib/bindings/")]
+pub struct FileData {
+    #[serde(flatten)]
+    pub data: MessageData,
+    pub file_name: String,
+    pub size: u64,
+    pub mime: String,
+    pub hash: Vec<u8>,
+}
+
+// The payload of data is the clip as mono opus, see audio.rs for the layout
+#[derive(TS, Serialize, Deserialize, Clone, Ord, PartialOrd, PartialEq, Eq, Debug)]
+#[ts(export)]
+#[ts(export_to="../src/lib/bindings/")]
+pub struct AudioData {
+    #[serde(flatten)]
+    pub data: MessageData,
+    pub duration_ms: u32,
+    pub sample_rate: u32,
+}
+
+#[derive(TS, Serialize, Deserialize, Clone, Ord, PartialOrd, PartialEq, Eq, Debug)]
+#[ts(export)]
+#[ts(export_to="../src/lib/bindings/")]
+pub struct DirectData {
+    #[serde(flatten)]
+    pub data: MessageData,
+    pub to: u32, // uid of the only peer it is sent to
+}
+
+// The payload of data is empty
+#[derive(TS, Serialize, Deserialize, Clone, Ord, PartialOrd, PartialEq, Eq, Debug)]
+#[ts(export)]
+#[ts(export_to="../src/lib/bindings/")]
+pub struct GroupData {
+    #[serde(flatten)]
+    pub data: MessageData,
+    pub gid: u32,
+    pub group_name: String,
+    pub members: Vec<u32>, // uids, including whoever sent it unless they are leaving
+}
+
+#[derive(TS, Serialize, Deserialize, Clone, Ord, PartialOrd, PartialEq, Eq, Debug)]
+#[ts(export)]
+#[ts(export_to="../src/lib/bindings/")]
+pub struct GroupMsgData {
+    #[serde(flatten)]
+    pub data: MessageData,
+    pub gid: u32,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gzip(bytes: &[u8]) -> Vec<u8> {
+        let mut e = GzEncoder::new(Vec::new(), Compression::default());
+        e.write_all(bytes).unwrap();
+        e.finish().unwrap()
+    }
+
+    fn framed(body: Vec<u8>) -> Vec<u8> {
+        [(body.len() as u64).to_le_bytes().to_vec(), body].concat()
     }
-}
\ No newline at end of file
+
+    fn decode(body: Vec<u8>) -> Result<Message, DecodeError> {
+        Message::from_network(&framed(body), &MessageLimits::default())
+    }
+
+    fn text(len: usize) -> Message {
+        Message::Text(MessageData::new("someone".to_owned(), 7, 42, 1_700_000_000, vec![255; len]))
+    }
+
+    #[test]
+    fn round_trips_in_both_formats() {
+        for format in [WireFormat::Json, WireFormat::Binary] {
+            let msg = text(100);
+            match Message::from_network(&msg.to_network(format), &MessageLimits::default()) {
+                Ok(Message::Text(data)) => assert_eq!(data.payload, vec![255; 100]),
+                other => panic!("expected Text, got {other:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_truncated_frames() {
+        let limits = MessageLimits::default();
+        assert!(matches!(
+            Message::from_network(&[0; 4], &limits),
+            Err(DecodeError::TruncatedHeader { len: 4 })
+        ));
+
+        let mut buf = text(100).to_network(WireFormat::Json);
+        buf.pop();
+        assert!(matches!(
+            Message::from_network(&buf, &limits),
+            Err(DecodeError::TruncatedBody { .. })
+        ));
+    }
+
+    #[test]
+    fn rejects_huge_length_prefixes() {
+        let buf = u64::MAX.to_le_bytes();
+        assert!(matches!(
+            Message::from_network(&buf, &MessageLimits::default()),
+            Err(DecodeError::FrameTooLarge { len: u64::MAX, .. })
+        ));
+    }
+
+    #[test]
+    fn rejects_bad_bodies() {
+        assert!(matches!(decode(b"not gzip".to_vec()), Err(DecodeError::BadGzip(_))));
+        assert!(matches!(decode(gzip(&[0xff, 0xfe])), Err(DecodeError::InvalidUtf8(_))));
+        assert!(matches!(
+            decode(gzip(br#"{"Text":{"name":1}}"#)),
+            Err(DecodeError::SchemaMismatch(_))
+        ));
+        assert!(matches!(
+            decode(vec![codec::MAGIC, codec::BINARY_VERSION + 1, 0]),
+            Err(DecodeError::UnsupportedVersion(_))
+        ));
+    }
+
+    #[test]
+    fn reports_unknown_variants_by_name() {
+        match decode(gzip(br#"{"Hovercraft":1}"#)) {
+            Err(DecodeError::UnknownVariant(name)) => assert_eq!(name, "Hovercraft"),
+            other => panic!("expected UnknownVariant, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn checks_each_type_against_its_own_limit() {
+        let limits = MessageLimits::default();
+        // too big for a Text, but not for an Image
+        let len = limits.text.decompressed as usize;
+        for format in [WireFormat::Json, WireFormat::Binary] {
+            match Message::from_network(&text(len).to_network(format), &limits) {
+                Err(DecodeError::MessageTooLarge { msg_type, limit, .. }) => {
+                    assert_eq!(msg_type, "Text");
+                    assert_eq!(limit.decompressed, limits.text.decompressed);
+                },
+                other => panic!("expected MessageTooLarge, got {other:?}"),
+            }
+
+            let image = Message::Image(MessageData::new("someone".to_owned(), 7, 42, 1_700_000_000, vec![255; len]));
+            assert!(Message::from_network(&image.to_network(format), &limits).is_ok());
+        }
+    }
+
+    #[test]
+    fn stops_inflating_past_the_limit() {
+        let limits = MessageLimits::default();
+        let max = limits.max_decompressed();
+        // a few KiB of gzip that would inflate to far more than we allow
+        let bomb = gzip(&vec![b' '; (max * 4) as usize]);
+        assert!((bomb.len() as u64) < limits.max_compressed());
+        assert!(matches!(decode(bomb), Err(DecodeError::DecompressedTooLarge { max: m }) if m == max));
+
+        let exact = gzip(&[b' '; 16]);
+        assert_eq!(inflate(&exact, 16).unwrap().len(), 16);
+        assert!(matches!(inflate(&exact, 15), Err(DecodeError::DecompressedTooLarge { max: 15 })));
+    }
+}
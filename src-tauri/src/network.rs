@@ -1,467 +1,2235 @@
-use std::{net::{UdpSocket, TcpStream, IpAddr, SocketAddr, TcpListener, Ipv4Addr}, sync::{Mutex, Arc}, time::Duration, collections::HashSet, io::{Write, Read}};
+use std::{net::{IpAddr, SocketAddr, Ipv4Addr, Ipv6Addr}, sync::{Mutex, Arc}, time::{Duration, Instant}, collections::{HashSet, HashMap}};
 use tauri::{State, async_runtime, Manager};
-use const_format::formatcp;
-use crate::{message::{Message, MessageData, HEADER_LEN}, utilities::{gen_rand_id, get_curr_time, send_msg_to_frontend, parse_img_str}, profile::Profile};
-use crate::utilities;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, oneshot};
+use tokio::sync::mpsc::error::TrySendError;
+use serde::{Serialize, Deserialize};
+use ts_rs::TS;
+use socket2::{Socket, Domain, Type, Protocol};
+use crate::{message::{self, Message, MessageData, BroadcastData, HelloData, AudioData, DirectData, GroupMsgData, MessageLimits, DecodeError, WireFormat, CAP_HEARTBEAT, CAP_RESUME, CAP_CHUNKS, CAP_FILES, CAP_ROOMS}, utilities::{gen_rand_id, get_curr_time, send_msg_to_frontend, parse_img_str}, profile::Profile};
+use crate::peer::{self, PeerId, PeerEvent, PeerError, PeerSender, Outgoing, Progress};
+use crate::noise::{self, Handshake, Session, NOISE_VERSION};
+use crate::{identity, trust};
+use crate::discovery::Discovery;
+use crate::files;
+use crate::audio;
+use crate::groups::{self, Groups};
+use crate::interfaces;
+use crate::mdns::Mdns;
+use crate::rooms::{self, NearbyRoom, RoomExchange};
 use crate::AppState;
-
-const BROADCAST_IP: &str = "255.255.255.255";
-const BROADCAST_PORT: &str = "59813";
-const BROADCAST_ADDR: &str = formatcp!("{BROADCAST_IP}:{BROADCAST_PORT}");
+// We try to listen on the first free port in this range, so there is a
+// predictable set of ports to open in a firewall. If they are all taken,
+// or ECTOCHAT_P2P_PORT is set to 0, the OS picks one for us.
 const MIN_P2P_PORT: u16 = 61000;
 const MAX_P2P_PORT: u16 = 61255;
+const P2P_PORT_ENV_VAR: &str = "ECTOCHAT_P2P_PORT";
+const CONNECT_TIMEOUT: u64 = 2; // give up connecting to a peer after 2s
+const STATIC_PEER_REDIAL_TIME: u64 = 5; // try to reconnect to static peers every 5s
+
+// Messages are handled as soon as they arrive, but discovery, redialing and
+// heartbeats are checked on a timer
+const HOUSEKEEPING_TIME: u64 = 200; // every 200ms
+const QUARANTINE_TIME: u64 = 60; // ignore peers that sent us garbage for 60s
+const SHUTDOWN_TIMEOUT: u64 = 1; // wait at most 1s for goodbyes to be sent
+const REFUSED_TIME: u64 = 10; // don't dial peers that turned us away again for 10s
+const HELLO_TIMEOUT: u64 = 10; // close connections that haven't said a Hello we accept after 10s
 
-const SLEEP_TIME: u64 = 100; // wait 100ms between tcp listener code
-const BROADCAST_SLEEP_TIME: u64 = 200; // wait 200ms between broadcast code
+// After a drop, wait 1s, 2s, 4s... (up to 60s) between attempts to redial
+// the peer, and give up after 10 tries
+const RECONNECT_BASE_TIME: u64 = 1;
+const RECONNECT_MAX_TIME: u64 = 60;
+const MAX_RECONNECT_ATTEMPTS: u32 = 10;
 
-#[derive(PartialEq)]
+// How many of our most recent mids we tell peers about when resuming
+const SYNC_WATERMARK_LEN: usize = 16;
+
+#[derive(PartialEq, Clone, Copy)]
 pub enum TcpStreamType {
     Read,
     Write,
     Both,
 }
 
-pub struct PeerConnection {
-    pub stream: TcpStream,
-    pub stream_type: TcpStreamType,
-    pub peer_profile: Option<Profile>, // set later once hello msg received
-    pub peer_addr: SocketAddr,
-    pub local_addr: SocketAddr,
+// Everything the network task knows about one connection. The stream itself
+// belongs to the connection's reader and writer tasks.
+struct Peer {
+    writer: PeerSender,
+    task: async_runtime::JoinHandle<()>, // the handshake, then reading unless it's our own write only stream
+    stream_type: TcpStreamType,
+    peer_profile: Option<Profile>, // set later once hello msg received
+    peer_addr: SocketAddr,
+    wire_format: WireFormat, // upgraded once hello msg received
+    expected_uid: Option<u32>, // set if we dialed them because of their broadcast
+    dialed: bool, // true if we opened the connection, false if they did
+    capabilities: Vec<String>, // set once hello msg received
+    peer_listen_addr: Option<SocketAddr>, // where to redial them, set once hello msg received
+    protocol_version: u16, // set once hello msg received
+    handshake_hash: Option<Vec<u8>>, // set once the handshake is done, if encrypted
+    // The other end of a connection to ourselves, going by their Noise key.
+    // Set once the handshake is done.
+    is_self: bool,
+    // Our side of the passphrase exchange if our room is locked, until we
+    // have their share, and the secret it gave us after. See rooms.rs.
+    room_exchange: Option<RoomExchange>,
+    room_secret: Option<[u8; 32]>,
+    said_hello: bool,
+    connected_at: Instant,
+    last_heard: Instant, // when we last read a whole msg from them
+}
+
+impl Peer {
+    // Who is on the other end, once they have said a Hello we accept. Who we
+    // expected when we dialed is only a claim from their broadcast, so it's
+    // never used to decide what to send them.
+    fn uid(&self) -> Option<u32> {
+        self.peer_profile.as_ref().map(|p| p.uid)
+    }
+
+    // The uid this connection holds in p2p_uids, which we take even while
+    // dialing so we don't dial anyone twice
+    fn held_uid(&self) -> Option<u32> {
+        self.uid().or(self.expected_uid)
+    }
+
+    // Whether they are in our room. Until they have said a Hello we accept,
+    // all we send them is our own Hello and all we take from them is theirs.
+    // Our own write only stream never gets a Hello back, so it goes by the
+    // handshake instead.
+    fn accepted(&self) -> bool {
+        self.peer_profile.is_some() || (self.stream_type == TcpStreamType::Write && self.is_self)
+    }
+
+    fn has_capability(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c == capability)
+    }
+
+    // Whether they can decode out. Anything they can't is never sent, since
+    // they would cut us off for it.
+    fn understands(&self, out: &Outgoing) -> bool {
+        match out {
+            Outgoing::Msg(msg) => msg.required_capability().map_or(true, |c| self.has_capability(c)),
+            _ => true,
+        }
+    }
+
+    fn send(&self, msg: Message) {
+        self.queue(Outgoing::Msg(msg));
+    }
+
+    // Never holds up the network task. If the peer's queue is full, out waits
+    // for room in the background, so it can end up behind later messages.
+    fn queue(&self, out: Outgoing) {
+        if !self.understands(&out) {
+            return;
+        }
+        match self.writer.try_send(out) {
+            // If the writer is gone, it has already told us why
+            Ok(()) | Err(TrySendError::Closed(_)) => {},
+            Err(TrySendError::Full(out)) => self.queue_in_background(vec![out]),
+        }
+    }
+
+    // Queues outs in order, waiting for room as needed
+    fn queue_in_background(&self, mut outs: Vec<Outgoing>) {
+        outs.retain(|out| self.understands(out));
+        let writer = self.writer.clone();
+        async_runtime::spawn(async move {
+            for out in outs {
+                if writer.send(out).await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+}
+
+impl Drop for Peer {
+    fn drop(&mut self) {
+        // The writer stops by itself once its channel closes
+        self.task.abort();
+    }
+}
+
+// Sent to the frontend whenever we forcibly cut off a peer, or a peer turns
+// us away, so the user can be told why
+#[derive(TS, Serialize, Clone)]
+#[ts(export)]
+#[ts(export_to="../src/lib/bindings/")]
+pub struct PeerDisconnect {
+    pub addr: String,
+    pub name: Option<String>,
+    pub uid: Option<u32>,
+    pub reason: String,
 }
 
-impl PeerConnection {
-    fn new(stream: TcpStream, stream_type: TcpStreamType) -> Self {
-        let peer_addr = stream.peer_addr().unwrap();
-        let local_addr = stream.local_addr().unwrap();
+// Sent to the frontend as chunks of a big message go out or come in, so the
+// user can see how far along it is. uid is the peer on the other end.
+#[derive(TS, Serialize, Clone)]
+#[ts(export)]
+#[ts(export_to="../src/lib/bindings/")]
+pub struct TransferProgress {
+    pub transfer_id: u32,
+    pub uid: Option<u32>,
+    pub sending: bool,
+    pub bytes: u64,
+    pub total: u64,
+}
+
+// How often we ping peers, and how long we wait to hear anything from them
+// before deciding they are gone. Peers that vanish without closing the
+// connection (e.g. wifi dropping out) would otherwise never be noticed.
+#[derive(TS, Serialize, Deserialize, Clone, Debug)]
+#[ts(export)]
+#[ts(export_to="../src/lib/bindings/")]
+pub struct HeartbeatConfig {
+    pub interval_secs: u32,
+    pub timeout_secs: u32,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        HeartbeatConfig { interval_secs: 5, timeout_secs: 15 }
+    }
+}
+
+// A peer we lost without a Goodbye, that we are trying to get back
+struct Reconnect {
+    addr: SocketAddr,
+    handshake: Handshake,
+    attempts: u32,
+    next_attempt: Instant,
+}
 
-        log::info!("Successfully made tcp stream to {}", peer_addr.ip());
-        Self { stream, stream_type, peer_profile: None, peer_addr, local_addr }
+// Who a quarantine is for. By uid once they have said Hello, otherwise by
+// the address we dialed. Never by ip alone, since that would catch every
+// other instance on the same machine.
+#[derive(PartialEq, Eq, Hash, Clone, Copy)]
+enum Offender {
+    Uid(u32),
+    Addr(SocketAddr),
+}
+
+impl std::fmt::Display for Offender {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Offender::Uid(uid) => write!(f, "uid={uid}"),
+            Offender::Addr(addr) => write!(f, "{addr}"),
+        }
     }
 }
 
-fn is_localhost_stream(stream: &TcpStream) -> bool {
-    stream.peer_addr().unwrap().ip() == stream.local_addr().unwrap().ip()
+fn reconnect_backoff(attempts: u32) -> Duration {
+    let secs = RECONNECT_BASE_TIME.saturating_mul(1 << attempts.min(16));
+    Duration::from_secs(secs.min(RECONNECT_MAX_TIME))
+}
+
+fn make_dropped_msg(profile: &Profile) -> Message {
+    Message::Dropped(MessageData::new(
+        profile.name.clone(),
+        profile.uid,
+        gen_rand_id(),
+        get_curr_time(),
+        profile.pic.clone(),
+    ))
+}
+
+// Our Hello for peer, proving our key and (if our room is locked) that we
+// know its passphrase on this connection
+fn make_hello_msg(state: &State<AppState>, peer: &Peer) -> Message {
+    let room = state.rooms.lock().unwrap().current().unwrap_or_default().to_owned();
+    let room_proof = match (&peer.room_secret, &peer.handshake_hash) {
+        (Some(secret), Some(handshake_hash)) => rooms::room_proof(secret, handshake_hash, peer.dialed),
+        _ => Vec::new(),
+    };
+    let profile = state.profile.lock().unwrap();
+    let handshake_sig = peer.handshake_hash.as_ref()
+        .map(|handshake_hash| state.identity.lock().unwrap().sign_hello(handshake_hash, peer.dialed, profile.uid))
+        .unwrap_or_default();
+    profile.make_hello_msg(state.connection.p2p_port, room, handshake_sig, room_proof)
+}
+
+fn say_hello(state: &State<AppState>, peer: &mut Peer) {
+    peer.said_hello = true;
+    peer.send(make_hello_msg(state, peer));
+}
+
+// What the rest of the app can ask the network task to do
+enum NetCommand {
+    SetActive(bool),
+    // Messages are queued by whoever is sending them, so that they are the
+    // ones kept waiting when a peer falls behind. Only writers for peers that
+    // have the capability, and are one of the uids in to if given, are sent back.
+    GetWriters {
+        to: Option<Vec<u32>>,
+        capability: Option<&'static str>,
+        reply: oneshot::Sender<Vec<PeerSender>>,
+    },
+    Connect { addr: String, reply: Option<oneshot::Sender<Result<(), String>>> },
+    ChangeRoom { room: Option<String>, goodbye: Message, reply: oneshot::Sender<Vec<Message>> },
+    Shutdown { goodbye: Message, done: std::sync::mpsc::Sender<()> },
+}
+
+// Results of work the network task hands off so it never has to wait
+enum NetEvent {
+    Accepted(TcpStream),
+    Dialed {
+        saddr: SocketAddr,
+        expected_uid: Option<u32>,
+        handshake: Handshake,
+        result: std::io::Result<TcpStream>,
+        reply: Option<oneshot::Sender<Result<(), String>>>,
+    },
+    Resolved {
+        addr: String,
+        result: std::io::Result<SocketAddr>,
+        reply: Option<oneshot::Sender<Result<(), String>>>,
+    },
 }
 
+// Handle to the network task. All connection state lives in the task, and
+// everything else talks to it over a channel.
 pub struct ConnectionState {
-    broadcast_socket: Arc<Mutex<UdpSocket>>,
-    p2p_connections: Arc<Mutex<Vec<PeerConnection>>>,
-    p2p_ips: Arc<Mutex<HashSet<IpAddr>>>,
-    p2p_listeners: Arc<Mutex<Vec<TcpListener>>>,
+    pub discovery: Discovery,
+    pub mdns: Mdns,
+    p2p_port: u16, // advertised in our broadcasts, same for both listeners
 
-    active: Arc<Mutex<bool>>,
+    commands: mpsc::UnboundedSender<NetCommand>,
+    // Handed over to the network task when it starts
+    command_rx: Mutex<Option<mpsc::UnboundedReceiver<NetCommand>>>,
+    p2p_listeners: Mutex<Vec<std::net::TcpListener>>, // one for IPv4 and (if possible) one for IPv6
+
+    // Settings the network task reads, but that commands can change at any
+    // time. Nothing else is ever locked while one of these is.
+    limits: Arc<Mutex<MessageLimits>>,
+    heartbeat: Mutex<HeartbeatConfig>,
+    // Names of interfaces the user doesn't want us broadcasting on, e.g. VPNs
+    excluded_interfaces: Mutex<HashSet<String>>,
+
+    // Our static keys for the handshake, see noise.rs. Connections whose
+    // other end has the same public key are to ourselves.
+    noise_key: Vec<u8>,
+    noise_public_key: Vec<u8>,
 }
 
 impl ConnectionState {
     pub fn new() -> ConnectionState {
-        let socket = UdpSocket::bind(format!("0.0.0.0:{BROADCAST_PORT}")).unwrap();
-        socket.set_broadcast(true).unwrap();
-        socket.set_nonblocking(true).unwrap();
-
-        let listeners = (MIN_P2P_PORT..=MAX_P2P_PORT)
-            .filter_map(|port| {
-                let res = TcpListener::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0,0,0,0)), port));
-                if let Ok(listener) = &res {
-                    let _ = listener.set_nonblocking(true);
-                }
-                res.ok()
-            })
-            .collect();
+        let listener = bind_p2p_listener();
+        listener.set_nonblocking(true).unwrap();
+        let p2p_port = listener.local_addr().unwrap().port();
+        log::info!("Listening for tcp connections on port {p2p_port}");
+
+        let mut listeners = vec![listener];
+        match bind_p2p_listener_v6(p2p_port) {
+            Ok(listener_v6) => listeners.push(listener_v6),
+            Err(e) => log::warn!("Could not listen for IPv6 tcp connections on port {p2p_port}: {e}"),
+        }
+
+        let (commands, command_rx) = mpsc::unbounded_channel();
+        let noise_keypair = noise::generate_keypair();
 
         ConnectionState {
-            broadcast_socket: Arc::new(Mutex::new(socket)),
-            p2p_connections: Arc::new(Mutex::new(Vec::new())),
-            p2p_ips: Arc::new(Mutex::new(HashSet::new())),
-            p2p_listeners: Arc::new(Mutex::new(listeners)),
-            active: Arc::new(Mutex::new(false)),
+            discovery: Discovery::new(),
+            mdns: Mdns::new(),
+            p2p_port,
+            commands,
+            command_rx: Mutex::new(Some(command_rx)),
+            p2p_listeners: Mutex::new(listeners),
+            limits: Arc::new(Mutex::new(MessageLimits::default())),
+            heartbeat: Mutex::new(HeartbeatConfig::default()),
+            excluded_interfaces: Mutex::new(HashSet::new()),
+            noise_key: noise_keypair.private,
+            noise_public_key: noise_keypair.public,
         }
     }
 
     pub fn set_active(&self, val: bool) {
-        *self.active.lock().unwrap() = val;
+        let _ = self.commands.send(NetCommand::SetActive(val));
     }
-}
 
-pub fn run_background_threads(window: tauri::Window) {
-    let state: State<AppState> = window.state();
+    // Queues msgs for every peer we can write to. Waits while any of their
+    // queues are full, so a slow peer slows down sending rather than being
+    // mistaken for a dropped one.
+    pub async fn send(&self, msgs: Vec<Message>) -> Result<(), String> {
+        self.send_to(None, msgs).await
+    }
 
-    let active = state.connection.active.clone();
-    let w1 = window.clone();
-    async_runtime::spawn(async move {
-        loop {
-            if *active.lock().unwrap() {
-                manage_p2p_connections(&w1);
-                listen_for_p2p_connections(&w1);
+    // Same as send, but only to the peers with the uids in to if given
+    pub async fn send_to(&self, to: Option<Vec<u32>>, msgs: Vec<Message>) -> Result<(), String> {
+        self.send_to_reached(to, msgs).await.map(|_| ())
+    }
+
+    // Same as send_to, but returns how many peers were sent the last of msgs.
+    // Peers that aren't connected or don't understand it aren't counted.
+    pub async fn send_to_reached(&self, to: Option<Vec<u32>>, msgs: Vec<Message>) -> Result<usize, String> {
+        let mut reached = 0;
+        for msg in msgs {
+            let (reply, rx) = oneshot::channel();
+            let capability = msg.required_capability();
+            self.commands.send(NetCommand::GetWriters { to: to.clone(), capability, reply })
+                .map_err(|_| "Network is not running".to_owned())?;
+            let writers = rx.await.map_err(|_| "Network is not running".to_owned())?;
+
+            reached = 0;
+            for writer in writers {
+                // an error means they dropped while we were waiting
+                if writer.send(Outgoing::Msg(msg.clone())).await.is_ok() {
+                    reached += 1;
+                }
             }
-            tokio::time::sleep(Duration::from_millis(SLEEP_TIME)).await;
         }
-    });
+        Ok(reached)
+    }
 
-    let active = state.connection.active.clone();
-    let w2 = window.clone();
-    async_runtime::spawn(async move {
-        loop {
-            if *active.lock().unwrap() {
-                send_broadcast(&w2);
-                listen_for_broadcasts(&w2);
-            }
-            tokio::time::sleep(Duration::from_millis(BROADCAST_SLEEP_TIME)).await;
+    pub async fn connect(&self, addr: String) -> Result<(), String> {
+        let (reply, rx) = oneshot::channel();
+        self.commands.send(NetCommand::Connect { addr, reply: Some(reply) })
+            .map_err(|_| "Network is not running".to_owned())?;
+        rx.await.map_err(|_| "Network is not running".to_owned())?
+    }
+
+    // Says goodbye to everyone in our room and moves us into room, or out of
+    // every room if None. Returns the history of the room we moved into.
+    pub async fn change_room(&self, room: Option<String>, goodbye: Message) -> Result<Vec<Message>, String> {
+        let (reply, rx) = oneshot::channel();
+        self.commands.send(NetCommand::ChangeRoom { room, goodbye, reply })
+            .map_err(|_| "Network is not running".to_owned())?;
+        rx.await.map_err(|_| "Network is not running".to_owned())
+    }
+
+    // Says goodbye to everyone and closes every connection. Blocks until the
+    // goodbyes have been written, or SHUTDOWN_TIMEOUT passes.
+    pub fn shutdown(&self, goodbye: Message) {
+        let (done, done_rx) = std::sync::mpsc::channel();
+        if self.commands.send(NetCommand::Shutdown { goodbye, done }).is_ok() {
+            let _ = done_rx.recv_timeout(Duration::from_secs(SHUTDOWN_TIMEOUT));
+        }
+    }
+
+    pub fn limits(&self) -> MessageLimits {
+        self.limits.lock().unwrap().clone()
+    }
+
+    pub fn heartbeat(&self) -> HeartbeatConfig {
+        self.heartbeat.lock().unwrap().clone()
+    }
+
+    pub fn excluded_interfaces(&self) -> HashSet<String> {
+        self.excluded_interfaces.lock().unwrap().clone()
+    }
+
+    pub fn set_excluded_interfaces(&self, excluded: HashSet<String>) {
+        *self.excluded_interfaces.lock().unwrap() = excluded;
+    }
+}
+
+fn bind_p2p_listener() -> std::net::TcpListener {
+    let bind = |port: u16| std::net::TcpListener::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port));
+
+    if let Ok(port_str) = std::env::var(P2P_PORT_ENV_VAR) {
+        match port_str.parse::<u16>() {
+            Ok(port) => match bind(port) {
+                Ok(listener) => return listener,
+                Err(e) => log::error!("Could not listen on {P2P_PORT_ENV_VAR}={port}: {e}"),
+            },
+            Err(e) => log::error!("Invalid {P2P_PORT_ENV_VAR}={port_str}: {e}"),
         }
+    }
+
+    (MIN_P2P_PORT..=MAX_P2P_PORT)
+        .find_map(|port| bind(port).ok())
+        .unwrap_or_else(|| bind(0).unwrap())
+}
+
+// IPv6 peers (e.g. found via link-local multicast) connect to the same port
+// we advertise for IPv4
+fn bind_p2p_listener_v6(port: u16) -> std::io::Result<std::net::TcpListener> {
+    let socket = Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_only_v6(true)?;
+    socket.bind(&SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port).into())?;
+    socket.listen(128)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket.into())
+}
+
+// Port is optional, since most people will be listening on the default one
+async fn resolve_peer_addr(addr: &str) -> std::io::Result<SocketAddr> {
+    let saddrs: Vec<SocketAddr> = match tokio::net::lookup_host(addr).await {
+        Ok(saddrs) => saddrs.collect(),
+        Err(_) => tokio::net::lookup_host((addr, MIN_P2P_PORT)).await?.collect(),
+    };
+    saddrs.into_iter().next().ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "no addresses found"))
+}
+
+// Port 0 is for older builds, which don't say which port they are listening
+// on, so try all the ones they could be
+async fn connect(saddr: SocketAddr) -> std::io::Result<TcpStream> {
+    if saddr.port() != 0 {
+        return TcpStream::connect(saddr).await;
+    }
+    let saddrs: Vec<SocketAddr> = (MIN_P2P_PORT..=MAX_P2P_PORT)
+        .map(|port| SocketAddr::new(saddr.ip(), port))
+        .collect();
+    TcpStream::connect(&saddrs[..]).await
+}
+
+// The mids of the last few chat messages we have, newest last
+fn make_sync_watermark(msg_history: &[Message]) -> Vec<u32> {
+    let mut seen: Vec<u32> = msg_history.iter().rev()
+        .filter_map(|msg| msg.chat_data().map(|data| data.mid))
+        .take(SYNC_WATERMARK_LEN)
+        .collect();
+    seen.reverse();
+    seen
+}
+
+// Everything in our history after the newest message the peer has seen, that
+// they don't already have and was meant for them. If we don't share any
+// messages with them there is nothing to catch up on, e.g. they are brand new
+// to the room.
+fn find_missed_msgs(msg_history: &[Message], seen: &[u32], peer_uid: Option<u32>, groups: &Groups) -> Vec<Message> {
+    let last_seen = msg_history.iter().rposition(|msg| {
+        msg.chat_data().map_or(false, |data| seen.contains(&data.mid))
     });
+    let last_seen = match last_seen {
+        Some(index) => index,
+        None => return Vec::new(),
+    };
+
+    msg_history[last_seen + 1..].iter()
+        .filter(|msg| {
+            msg.chat_data().map_or(false, |data| !seen.contains(&data.mid) && Some(data.uid) != peer_uid)
+                && msg.recipient().map_or(true, |to| Some(to) == peer_uid)
+                && msg.group().map_or(true, |gid| peer_uid.map_or(false, |uid| groups.is_member(gid, uid)))
+        })
+        .cloned()
+        .collect()
 }
 
-fn manage_p2p_connections(window: &tauri::Window) {
+// Starts the network task. Only the first call does anything, since there is
+// only ever one network task.
+pub fn run_network_task(window: tauri::Window) {
     let state: State<AppState> = window.state();
 
-    let mut outgoing_acks: Vec<Message> = vec![];
-    let mut killed_connections: HashSet<SocketAddr> = HashSet::new();
-
-    {
-        let mut p2p_connections = state.connection.p2p_connections.lock().unwrap();
-        for connection in p2p_connections.iter_mut() {
-            if connection.stream_type == TcpStreamType::Write {
-                // If this stream should only be used for writing, skip
-                // because this function handles listening
-                continue
-            }
+    let commands = match state.connection.command_rx.lock().unwrap().take() {
+        Some(commands) => commands,
+        None => {
+            log::debug!("Network task is already running");
+            return;
+        },
+    };
+    let std_listeners = std::mem::take(&mut *state.connection.p2p_listeners.lock().unwrap());
 
-            let mut buf = [0u8; HEADER_LEN];
-            match connection.stream.peek(&mut buf) {
-                Ok(num_bytes_read) => {
-                    if num_bytes_read < HEADER_LEN{
-                        log::trace!("Only {num_bytes_read} on the wire, so not enough for header. Skipping.");
-                        continue
-                    }
+    async_runtime::spawn(async move {
+        let (peer_events_tx, peer_events) = mpsc::unbounded_channel();
+        let (net_events_tx, net_events) = mpsc::unbounded_channel();
 
-                    let msg_len = u64::from_le_bytes(buf); // len of msg object
-                    let full_msg_len = HEADER_LEN + msg_len as usize; // include 8 bytes from header
-                    let mut full_msg_buf = vec![0u8; full_msg_len];
-                    match connection.stream.peek(&mut full_msg_buf) {
-                        Ok(num_bytes_read) => {
-                            if num_bytes_read < full_msg_len {
-                                log::trace!("Only {num_bytes_read} bytes on the wire, when expecting {full_msg_len}. Skipping.");
-                                continue
-                            }
-
-                            // Ok... so this is where we have been trying to get this
-                            // whole time. Now we have the entire msg in the full_msg_buf
-                            // from 0..full_msg_len
-                            let rec_msg = Message::from_network(&full_msg_buf[0..full_msg_len]);
-
-                            // pull out the bytes we used from the buffer
-                            let _ = connection.stream.read_exact(&mut full_msg_buf);
-
-                            log::info!("Received {} byte {} message from {}", msg_len, rec_msg.get_type_str(), connection.peer_addr);
-
-                            // add to msg history
-                            {
-                                let mut msg_history = state.msg_history.lock().unwrap();
-                                msg_history.push(rec_msg.clone());
-                            }
-
-                            // Record profile if it is a new connection established
-                            {
-                                match &rec_msg {
-                                    Message::Hello(data) => {
-                                    // If this is a greeting from a new peer/user, we need to record their
-                                    // information so we can poll it later
-                                        let mut known_users = state.known_users.lock().unwrap();
-                                        let rec_profile = Profile {
-                                            name: data.name.clone(),
-                                            uid: data.uid, 
-                                            join_time: data.timestamp, 
-                                            pic: data.payload.clone(),
-                                        };
-                                        log::info!("Adding {} to known users.", rec_profile.name);
-                                        known_users.add_user(rec_profile.clone(), window);
-
-                                        // also add profile information to the connection
-                                        connection.peer_profile = Some(rec_profile);
-                                    },
-                                    Message::Goodbye(_) => {
-                                        // This peer is going to be shutting down soon, so we should
-                                        // clean up their connection status
-                                        log::info!("Goodbye received from {}", connection.peer_addr);
-                                        killed_connections.insert(connection.peer_addr);
-                                    },
-                                    _ => {},
-                                }
-                            }
-
-                            // Send ack msg back
-                            {
-                                match &rec_msg {
-                                    Message::Image(data) |
-                                    Message::Text(data)  => {
-                                        // If from self, don't ACK
-                                        let uid = {
-                                            state.profile.lock().unwrap().uid
-                                        };
-
-                                        if data.uid != uid {
-                                            // Send back Ack
-                                            let ack_msg = Message::Ack{
-                                                uid: uid,
-                                                mid: data.mid,
-                                            };
-
-                                            outgoing_acks.push(ack_msg);
-                                        }
-                                    },
-                                    // don't care about hello or broadcast msg, 
-                                    // also, more importantly, don't want to ack acks
-                                    // because that would create an infinite loop of
-                                    // packets bouncing across the network 
-                                    _ => {} 
-                                }
-                            }
-
-                            send_msg_to_frontend(&rec_msg, &window);
-                        },
-                        Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                            continue
-                        },
-                        Err(e) => {
-                            log::error!("Error peeking tcp stream for full msg: {e:#?}");
-                            continue
-                        }
-                    }
-                },
-                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                    // no data, so don't wait around for it to come
-                    continue
+        for std_listener in std_listeners {
+            match TcpListener::from_std(std_listener) {
+                Ok(listener) => {
+                    async_runtime::spawn(accept_connections(listener, net_events_tx.clone()));
                 },
-                Err(e) => {
-                    log::error!("Error peeking tcp stream for header: {e:#?}");
-                    continue
-                }
+                Err(e) => log::error!("Error listening for tcp connections: {e}"),
             }
         }
 
-        // Get rid of connections we received a Goodbye message for
-        p2p_connections.retain(|conn| {
-            !killed_connections.contains(&conn.peer_addr)
-        });
+        let network = Network::new(window, peer_events_tx, net_events_tx);
+        network.run(commands, peer_events, net_events).await;
+    });
+}
 
-        // Remove from set of IPs we're talking to
-        let mut p2p_ips = state.connection.p2p_ips.lock().unwrap();
-        for peer_addr in killed_connections {
-            log::trace!("Removing ips from set: {}", peer_addr.ip());
-            p2p_ips.remove(&peer_addr.ip());
+async fn accept_connections(listener: TcpListener, net_events: mpsc::UnboundedSender<NetEvent>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                if net_events.send(NetEvent::Accepted(stream)).is_err() {
+                    break;
+                }
+            },
+            Err(e) => log::error!("Error accepting tcp connection: {e}"),
         }
     }
-
-    send_msgs_to_all_peers(outgoing_acks, window);
 }
 
-fn send_broadcast(window: &tauri::Window) {
-    let state: State<AppState> = window.state();
+// The network task. It owns every connection and everything we know about
+// them, so none of it needs locking.
+struct Network {
+    window: tauri::Window,
 
-    let msg = Message::Broadcast(state.profile.lock().unwrap().uid).to_network();
+    peers: HashMap<PeerId, Peer>,
+    next_peer_id: PeerId,
 
-    match state.connection.broadcast_socket.lock().unwrap().send_to(&msg, BROADCAST_ADDR) {
-        Ok(bytes_written) => {
-            if bytes_written != msg.len() {
-                log::error!("Error: could not send entire broadcast message");
-            }
-        },
-        Err(e) => {
-            log::error!("Error sending broadcast: {e:#?}");
-        },
-    };
-}
+    // Everyone we are connected to or in the middle of dialing, including
+    // ourselves. Several peers can share an IP (NAT, or a few instances on
+    // one machine) so this is what stops us connecting to someone twice.
+    p2p_uids: HashSet<u32>,
 
-fn listen_for_p2p_connections(window: &tauri::Window) {
-    let state: State<AppState> = window.state();
+    // Peers that sent us frames we couldn't decode. We refuse to talk to
+    // them again until the quarantine expires.
+    quarantined: HashMap<Offender, Instant>,
+    decode_failures: HashMap<Offender, u32>,
+    // Peers that sent us a Disconnect, e.g. because their room is full, by
+    // uid. We wait until the time given before dialing them again.
+    refused_by: HashMap<u32, Instant>,
+    // The same for connections we dialed, by the address we dialed, since
+    // static peers are dialed before we know their uid. Also covers the
+    // ones we turned away ourselves, or they would be redialed right away.
+    refused_at: HashMap<SocketAddr, Instant>,
+    // uids that have told us, in a broadcast or over mDNS, that they can
+    // encrypt. Anything in plaintext claiming to be them is refused.
+    noise_uids: HashSet<u32>,
 
-    let p2p_listeners = state.connection.p2p_listeners.lock().unwrap();
-    for listener in p2p_listeners.iter() {
-        for stream in listener.incoming() {
-            match stream {
-                Ok(mut stream) => {
-                    let _ = stream.set_nonblocking(true);
-                    {
-                        let mut p2p_ips = state.connection.p2p_ips.lock().unwrap();
-                        let peer_ip = stream.peer_addr().unwrap().ip();
-                        // keep track that we have an active connection with this ip
-                        p2p_ips.insert(peer_ip); 
-                    }
+    reconnects: HashMap<u32, Reconnect>, // keyed by uid
+    last_static_dial: Option<Instant>,
+    last_heartbeat: Instant,
 
-                    let stream_type = if is_localhost_stream(&stream) {
-                        TcpStreamType::Read
-                    } else {
-                        let profile = state.profile.lock().unwrap();
-                        // Send initial hello msg
-                        if let Err(e) = stream.write(&profile.make_hello_msg().to_network()) {
-                            log::error!("Error writing hello msg to listen stream: {e:#?}");
-                        }
+    active: bool,
 
-                        TcpStreamType::Both
-                    };
+    peer_events: mpsc::UnboundedSender<PeerEvent>,
+    net_events: mpsc::UnboundedSender<NetEvent>,
+}
+
+impl Network {
+    fn new(
+        window: tauri::Window,
+        peer_events: mpsc::UnboundedSender<PeerEvent>,
+        net_events: mpsc::UnboundedSender<NetEvent>,
+    ) -> Network {
+        Network {
+            window,
+            peers: HashMap::new(),
+            next_peer_id: 0,
+            p2p_uids: HashSet::new(),
+            quarantined: HashMap::new(),
+            decode_failures: HashMap::new(),
+            refused_by: HashMap::new(),
+            refused_at: HashMap::new(),
+            noise_uids: HashSet::new(),
+            reconnects: HashMap::new(),
+            last_static_dial: None,
+            last_heartbeat: Instant::now(),
+            active: false,
+            peer_events,
+            net_events,
+        }
+    }
 
-                    {
-                        let mut p2p_streams = state.connection.p2p_connections.lock().unwrap();
-                        // add stream so we start doing listening on it
-                        p2p_streams.push(PeerConnection::new(stream, stream_type)); 
+    async fn run(
+        mut self,
+        mut commands: mpsc::UnboundedReceiver<NetCommand>,
+        mut peer_events: mpsc::UnboundedReceiver<PeerEvent>,
+        mut net_events: mpsc::UnboundedReceiver<NetEvent>,
+    ) {
+        let mut housekeeping = tokio::time::interval(Duration::from_millis(HOUSEKEEPING_TIME));
+        housekeeping.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        loop {
+            tokio::select! {
+                Some(command) = commands.recv() => {
+                    if !self.handle_command(command) {
+                        break;
                     }
                 },
-                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                    break;
+                Some(event) = peer_events.recv() => match event {
+                    PeerEvent::Established(id, session) => self.handle_established(id, session),
+                    PeerEvent::Received(id, msg) => self.handle_msg(id, msg),
+                    PeerEvent::Progress(id, progress) => self.handle_progress(id, progress),
+                    PeerEvent::Closed(id, err) => self.handle_closed(id, err),
                 },
-                Err(e) => log::error!("{e}"),
+                Some(event) = net_events.recv() => self.handle_net_event(event),
+                _ = housekeeping.tick() => self.housekeeping(),
             }
         }
+        log::info!("Network task stopped");
     }
-}
 
-fn listen_for_broadcasts(window: &tauri::Window) {
-    let state: State<AppState> = window.state();
+    fn own_uid(&self) -> u32 {
+        let state: State<AppState> = self.window.state();
+        let uid = state.profile.lock().unwrap().uid;
+        uid
+    }
 
-    let bcast_socket = state.connection.broadcast_socket.lock().unwrap();
-    let mut buf = [0; 100]; // broadcast msgs will be tiny 
-    match bcast_socket.recv_from(&mut buf) {
-        Ok((_received, rec_saddr)) => {
-            let rec_msg = Message::from_network(&buf);
-            match &rec_msg {
-                Message::Broadcast(rec_uid) => {
-                    let profile = state.profile.lock().unwrap();
-                    if *rec_uid > profile.uid {
-                        log::trace!(
-                            "Received broadcast from uid={}. Their uid is greater, so waiting for them to establish a connection.",
-                            *rec_uid
-                        );
-                        return 
-                    }
-                },
-                _ => {
-                    log::warn!("Received non-broadcast msg on the udp socket: {buf:#?}");
-                    return;
+    fn own_room(&self) -> Option<String> {
+        let state: State<AppState> = self.window.state();
+        let room = state.rooms.lock().unwrap().current().map(str::to_owned);
+        room
+    }
+
+    // Returns false once we should stop
+    fn handle_command(&mut self, command: NetCommand) -> bool {
+        match command {
+            NetCommand::SetActive(val) => self.active = val,
+            NetCommand::GetWriters { to, capability, reply } => {
+                let writers = self.peers.values()
+                    .filter(|peer| peer.stream_type != TcpStreamType::Read && peer.accepted())
+                    .filter(|peer| to.as_ref().map_or(true, |to| peer.uid().map_or(false, |uid| to.contains(&uid))))
+                    .filter(|peer| capability.map_or(true, |c| peer.has_capability(c)))
+                    .map(|peer| peer.writer.clone())
+                    .collect();
+                let _ = reply.send(writers);
+            },
+            NetCommand::Connect { addr, reply } => self.connect_to_static_peer(addr, reply),
+            NetCommand::ChangeRoom { room, goodbye, reply } => {
+                let history = self.change_room(room, goodbye);
+                let _ = reply.send(history);
+            },
+            NetCommand::Shutdown { goodbye, done } => {
+                for peer in self.peers.values().filter(|peer| peer.stream_type != TcpStreamType::Read) {
+                    peer.queue_in_background(vec![Outgoing::Close(Some(Box::new(goodbye.clone())), done.clone())]);
                 }
-            }
+                self.peers.clear();
+                return false;
+            },
+        }
+        true
+    }
 
-            let ip = rec_saddr.ip();
-            
-            let mut p2p_ips = state.connection.p2p_ips.lock().unwrap();
-            if p2p_ips.contains(&ip) {
-                log::trace!("Already received broadcast from {ip}, so ignoring");
-            } else {
-                log::trace!("New broadcast from {ip}, so attempting to establish connection.");
-                p2p_ips.insert(ip);
-
-                let possible_tcp_saddrs: Vec<SocketAddr> = (MIN_P2P_PORT..=MAX_P2P_PORT).map(|port| {
-                    SocketAddr::new(ip, port)
-                }).collect();
-                match TcpStream::connect(&possible_tcp_saddrs[..]) {
-                    Ok(mut stream) => {
-                        let _ = stream.set_nonblocking(true);
-                        {
-                            let profile = state.profile.lock().unwrap(); 
-                            if let Err(e) = stream.write(&profile.make_hello_msg().to_network()) {
-                                log::error!("Error writing hello msg to connect stream: {e:#?}");
-                            }
+    fn handle_net_event(&mut self, event: NetEvent) {
+        match event {
+            NetEvent::Accepted(stream) => {
+                // We don't know who they are until they say Hello, which is
+                // also how we find out if they are quarantined. Whether they
+                // are us comes from the handshake.
+                self.add_peer(stream, TcpStreamType::Both, Handshake::Respond, None);
+            },
+            NetEvent::Dialed { saddr, expected_uid, handshake, result, reply } => {
+                let result = match result {
+                    Ok(stream) => {
+                        let own_uid = self.own_uid();
+                        let stream_type = if expected_uid == Some(own_uid) {
+                            // We have made the stream with ourselves, so now we can tell the frontend
+                            // to start displaying the chatting screen
+                            let _ = self.window.emit("evt_start_chatting", "");
+
+                            TcpStreamType::Write
+                        } else {
+                            TcpStreamType::Both
+                        };
+                        if let Some(uid) = expected_uid {
+                            self.reconnects.remove(&uid);
+                        }
+                        self.add_peer(stream, stream_type, handshake, expected_uid);
+                        Ok(())
+                    },
+                    Err(e) => {
+                        log::debug!("Error establishing connection with {saddr}: {e}");
+                        if let Some(uid) = expected_uid {
+                            self.forget_uid(uid);
+                            self.reconnect_failed(uid, &e);
                         }
-                        {
-                            let stream_type = if is_localhost_stream(&stream) {
-                                // We have made the stream with ourselves, so now we can tell the frontend
-                                // to start displaying the chatting screen
-                                let _ = window.emit("evt_start_chatting", "");
-
-                                TcpStreamType::Write
-                            } else {
-                                TcpStreamType::Both
-                            };
-
-                            let mut p2p_connections = state.connection.p2p_connections.lock().unwrap();
-                            p2p_connections.push(PeerConnection::new(stream, stream_type));
+                        Err(format!("Could not connect to {saddr}: {e}"))
+                    },
+                };
+                if let Some(reply) = reply {
+                    let _ = reply.send(result);
+                }
+            },
+            NetEvent::Resolved { addr, result, reply } => {
+                let result = result
+                    .map_err(|e| format!("Could not resolve {addr}: {e}"))
+                    .and_then(|tcp_saddr| self.check_static_peer(&addr, tcp_saddr));
+                match result {
+                    // the dial answers once it knows how it went. We don't
+                    // know what they speak, so wait and see if they say Hello.
+                    Ok(Some(tcp_saddr)) => self.dial(tcp_saddr, None, Handshake::Probe, reply),
+                    Ok(None) => {
+                        if let Some(reply) = reply {
+                            let _ = reply.send(Ok(()));
                         }
                     },
-                    Err(err) => {
-                        log::error!("Error establishing connection with {ip}, so removing IP from set. {err}");
-                        p2p_ips.remove(&ip);
+                    Err(e) => match reply {
+                        Some(reply) => { let _ = reply.send(Err(e)); },
+                        None => log::debug!("{e}"),
                     },
                 }
+            },
+        }
+    }
+
+    fn add_peer(&mut self, stream: TcpStream, stream_type: TcpStreamType, handshake: Handshake, expected_uid: Option<u32>) {
+        let state: State<AppState> = self.window.state();
+
+        let peer_addr = match stream.peer_addr() {
+            Ok(peer_addr) => peer_addr,
+            Err(e) => {
+                log::error!("Error getting address of new tcp stream: {e}");
+                return;
+            },
+        };
+        let _ = stream.set_nodelay(true);
+
+        let id = self.next_peer_id;
+        self.next_peer_id += 1;
+
+        // Anything sent before the handshake is done waits in the writer's queue
+        let (writer_tx, writer_rx) = peer::channel();
+        let limits = state.connection.limits.clone();
+        let key = state.connection.noise_key.clone();
+        let required = state.settings.lock().unwrap().encryption_required;
+        let events = self.peer_events.clone();
+        let task = async_runtime::spawn(async move {
+            let (reader, writer, session) = match peer::establish(stream, handshake, &key, required, limits).await {
+                Ok(established) => established,
+                Err(e) => {
+                    let _ = events.send(PeerEvent::Closed(id, e));
+                    return;
+                },
+            };
+            let _ = events.send(PeerEvent::Established(id, session));
+            async_runtime::spawn(peer::run_writer(id, writer, writer_rx, events.clone()));
+            if stream_type != TcpStreamType::Write {
+                peer::run_reader(id, reader, events).await;
             }
-        },
-        _ => (),
+        });
+
+        let peer = Peer {
+            writer: writer_tx,
+            task,
+            stream_type,
+            peer_profile: None,
+            peer_addr,
+            wire_format: if stream_type == TcpStreamType::Write { WireFormat::Binary } else { WireFormat::Json },
+            expected_uid,
+            dialed: handshake != Handshake::Respond,
+            // Our own write only stream never gets a Hello back, but we know
+            // what we understand
+            capabilities: if stream_type == TcpStreamType::Write { message::capabilities() } else { Vec::new() },
+            peer_listen_addr: None,
+            protocol_version: 0,
+            handshake_hash: None,
+            is_self: false,
+            room_exchange: None,
+            room_secret: None,
+            said_hello: false,
+            connected_at: Instant::now(),
+            last_heard: Instant::now(),
+        };
+
+        if stream_type == TcpStreamType::Write {
+            // Json would make our own voice messages and images several times
+            // bigger than everyone else's by the time we read them back
+            peer.queue(Outgoing::Format(WireFormat::Binary));
+            peer.queue(Outgoing::Chunks(true));
+        }
+
+        log::info!("Successfully made tcp stream to {peer_addr}");
+        self.peers.insert(id, peer);
     }
-}
 
-pub fn send_msgs_to_all_peers(msgs: Vec<Message>, window: &tauri::Window) {
-    let state: State<AppState> = window.state();
+    // Whoever dialed says Hello once the handshake is done, since a locked
+    // room's proof and the signature that proves our key is ours both need
+    // the handshake hash. In a locked room, both sides send their share of
+    // the passphrase exchange first, and whoever dialed waits for the other
+    // side's before saying Hello. Whoever was dialed waits until they have
+    // checked that Hello, so they only say who they are to someone who can
+    // join their room.
+    fn handle_established(&mut self, id: PeerId, session: Option<Session>) {
+        let state: State<AppState> = self.window.state();
+
+        let peer = match self.peers.get_mut(&id) {
+            Some(peer) => peer,
+            None => return, // already gone
+        };
+
+        peer.is_self = session.as_ref().map_or(false, |session| session.remote_key == state.connection.noise_public_key);
+        peer.handshake_hash = session.map(|session| session.handshake_hash);
+        if peer.stream_type == TcpStreamType::Write && !peer.is_self {
+            // Someone else is on our port, and would get everything we send
+            log::warn!("Connection to ourselves at {} was answered by someone else, closing it", peer.peer_addr);
+            if let Some(peer) = self.peers.remove(&id) {
+                if let Some(uid) = peer.held_uid() {
+                    self.forget_uid(uid);
+                }
+            }
+            return;
+        }
+
+        // Our own connection proves it's us with our Noise key, which nobody
+        // else has, so it doesn't need the passphrase
+        let key = state.rooms.lock().unwrap().current_key().copied();
+        if let (Some(key), Some(handshake_hash), false) = (key, &peer.handshake_hash, peer.is_self) {
+            let exchange = RoomExchange::new(&key, handshake_hash);
+            peer.send(Message::RoomShare(exchange.share()));
+            peer.room_exchange = Some(exchange);
+        }
+        if peer.dialed && peer.room_exchange.is_none() {
+            say_hello(&state, peer);
+        }
+    }
+
+    // Their share of the passphrase exchange for our locked room. Whoever
+    // dialed says Hello once they have it, since their proof needs the secret.
+    fn handle_room_share(&mut self, id: PeerId, share: &[u8]) {
+        let state: State<AppState> = self.window.state();
+        let room = self.own_room();
+
+        let peer = match self.peers.get_mut(&id) {
+            Some(peer) => peer,
+            None => return,
+        };
+        let exchange = match peer.room_exchange.take() {
+            Some(exchange) => exchange,
+            None if peer.room_secret.is_some() => {
+                log::warn!("Ignoring another room share from {}", peer.peer_addr);
+                return
+            },
+            None => {
+                // Whoever dialed us would wait for ours forever. Only builds
+                // that understand rooms send shares, so they can be told why.
+                if !peer.dialed {
+                    peer.capabilities = vec![CAP_ROOMS.to_owned()];
+                    let reason = match room {
+                        Some(room) => format!("Room {room} isn't locked"),
+                        None => "Not in a room".to_owned(),
+                    };
+                    self.refuse_connection(id, reason);
+                }
+                return
+            },
+        };
 
-    state.connection.p2p_connections.lock().unwrap().retain_mut(|connection| {
-        if connection.stream_type == TcpStreamType::Read {
-            return true; // keep but don't do anything
+        let handshake_hash = peer.handshake_hash.clone().unwrap_or_default();
+        match exchange.finish(share, &handshake_hash, peer.dialed) {
+            Ok(secret) => peer.room_secret = Some(secret),
+            Err(e) => {
+                peer.capabilities = vec![CAP_ROOMS.to_owned()];
+                self.refuse_connection(id, e);
+                return
+            },
         }
+        if peer.dialed && !peer.said_hello {
+            say_hello(&state, peer);
+        }
+    }
 
-        for msg in &msgs {
-            let msg_network = &msg.to_network();
-            let expected_bytes = msg_network.len();
+    fn handle_msg(&mut self, id: PeerId, mut rec_msg: Message) {
+        let window = self.window.clone();
+        let state: State<AppState> = window.state();
+        let own_uid = self.own_uid();
 
-            let stream_valid = match connection.stream.write(msg_network) {
-                Ok(bytes_written) => bytes_written == expected_bytes,
-                Err(_) => false,
+        if let Message::Hello(hello) = &mut rec_msg {
+            if self.is_quarantined(Offender::Uid(hello.data.uid)) {
+                self.refuse_peer(id, hello, "You are quarantined for sending bad messages".to_owned());
+                return
+            }
+            // Only our own connection, going by its Noise key, gets to use our uid
+            if hello.data.uid == own_uid && !self.peers.get(&id).map_or(false, |peer| peer.is_self) {
+                self.refuse_peer(id, hello, format!("uid {own_uid:x} is already in use"));
+                return
+            }
+            if let Some(reason) = self.downgrade_refusal(id, hello) {
+                self.refuse_peer(id, hello, reason);
+                return
+            }
+            let (handshake_hash, dialed) = match self.peers.get(&id) {
+                Some(peer) => (peer.handshake_hash.clone(), peer.dialed),
+                None => return, // already gone
             };
+            let proven = identity::check_hello(hello, handshake_hash.as_deref(), dialed, &state.known_users.lock().unwrap());
+            match proven {
+                Ok(true) => {},
+                // Anyone can put any key in a Hello, so one they haven't
+                // proven is no better than none
+                Ok(false) => hello.public_key.clear(),
+                Err(reason) => {
+                    trust::check_refused_hello(hello, &state, &window);
+                    self.refuse_peer(id, hello, reason);
+                    return
+                },
+            }
+            if let Some(reason) = self.room_refusal(id, hello, own_uid) {
+                self.refuse_peer(id, hello, reason);
+                return
+            }
+        }
 
-            if !stream_valid {
-                if let Some(profile) = &connection.peer_profile {
-                    // if we know who the connection was from, then manufacture a dropped msg
-                    // and insert into the frontend so that it can display the connection was dropped
-                    let dropped_msg = Message::Dropped(MessageData::new(
-                        profile.name.clone(),
-                        profile.uid,
-                        gen_rand_id(),
-                        get_curr_time(),
-                        profile.pic.clone(),
-                    ));
+        let peer = match self.peers.get_mut(&id) {
+            Some(peer) => peer,
+            None => return, // already gone
+        };
+        peer.last_heard = Instant::now();
 
-                    state.connection.p2p_ips.lock().unwrap().remove(&connection.peer_addr.ip());
+        // Besides their Hello and their share of the passphrase exchange, only
+        // being turned away is taken from a connection that isn't in our room yet
+        if !peer.accepted() && !matches!(rec_msg, Message::Hello(_) | Message::Disconnect { .. } | Message::RoomShare(_)) {
+            log::warn!("Dropping {} message from {}, who hasn't said Hello", rec_msg.get_type_str(), peer.peer_addr);
+            return
+        }
 
-                    send_msg_to_frontend(&dropped_msg, window);
-                    state.msg_history.lock().unwrap().push(dropped_msg);
+        // Anyone can claim to be anyone, so only the signature says who it's really from
+        if let Err(e) = identity::check(&mut rec_msg, &state.known_users.lock().unwrap()) {
+            log::warn!("Dropping message from {}: {e}", peer.peer_addr);
+            return
+        }
 
-                    log::warn!("Stream at {} no longer valid. Manufacturing drop message.", connection.peer_addr.ip());
-                }
-                return false; // remove from list, so connection will be dropped
+        if let (Some(to), Some(data)) = (rec_msg.recipient(), rec_msg.chat_data()) {
+            if to != own_uid && data.uid != own_uid {
+                log::warn!("Ignoring private message from {} meant for uid={to}", peer.peer_addr);
+                return
             }
         }
 
-        true
-    });
-}
+        if let (Some(gid), Some(data)) = (rec_msg.group(), rec_msg.chat_data()) {
+            let groups = state.groups.lock().unwrap();
+            if !groups.is_member(gid, own_uid) || !groups.is_member(gid, data.uid) {
+                log::warn!("Ignoring message from {} for group {gid}, which they or we aren't in", peer.peer_addr);
+                return
+            }
+        }
 
-#[tauri::command]
-pub fn cmd_send_text(msg: &str, state: State<AppState>, window: tauri::Window) {
-    let (name, uid) = {
-        let profile = state.profile.lock().unwrap();
-        (profile.name.clone(), profile.uid)
-    };
+        if let Message::Audio(audio) = &rec_msg {
+            if let Err(e) = audio::check(audio) {
+                log::warn!("Ignoring voice message from {}: {e}", peer.peer_addr);
+                return
+            }
+        }
 
-    let msg = Message::Text(MessageData::new(
-        name,
-        uid,
-        gen_rand_id(),
-        get_curr_time(),
-        msg.as_bytes().to_vec()
-    ));
+        // Heartbeats are between us and this peer only, so they
+        // don't go in the history or to the frontend
+        match rec_msg {
+            Message::Ping(nonce) => {
+                log::trace!("Ping {nonce} from {}", peer.peer_addr);
+                peer.send(Message::Pong(nonce));
+                return
+            },
+            Message::Pong(nonce) => {
+                log::trace!("Pong {nonce} from {}", peer.peer_addr);
+                return
+            },
+            Message::RoomShare(ref share) => {
+                let share = share.clone();
+                self.handle_room_share(id, &share);
+                return
+            },
+            Message::Sync { ref seen } => {
+                // Same goes for catching them up on what they missed
+                let missed = {
+                    let msg_history = state.msg_history.lock().unwrap();
+                    let groups = state.groups.lock().unwrap();
+                    find_missed_msgs(&msg_history, seen, peer.uid(), &groups)
+                };
+                log::info!("Resending {} missed messages to {}", missed.len(), peer.peer_addr);
+                peer.queue_in_background(missed.into_iter().map(Outgoing::Msg).collect());
+                return
+            },
+            Message::FileRequest { mid, offset } => {
+                // Files are only sent to whoever asks for them. The parts go
+                // straight to the writer, so check they can decode them here.
+                if !peer.has_capability(CAP_FILES) {
+                    log::warn!("{} asked for file {mid}, but didn't say they understand files", peer.peer_addr);
+                    return
+                }
+                match state.files.lock().unwrap().offered_path(mid) {
+                    Some(path) => {
+                        log::info!("Sending file {mid} to {} from {offset} bytes", peer.peer_addr);
+                        async_runtime::spawn(files::send_file(path, mid, offset, peer.writer.clone()));
+                    },
+                    None => log::warn!("{} asked for file {mid}, which we never offered", peer.peer_addr),
+                }
+                return
+            },
+            Message::GroupCreate(ref update) | Message::GroupUpdate(ref update) => {
+                // Groups are kept up to date in the background, the frontend
+                // only hears about the groups themselves. Changes are only
+                // ever sent by whoever made them, never passed on.
+                if peer.uid() != Some(update.data.uid) {
+                    log::warn!("Ignoring change to group {} by uid={} from {}", update.gid, update.data.uid, peer.peer_addr);
+                    return
+                }
+                let mut groups = state.groups.lock().unwrap();
+                if groups.apply(update, own_uid) {
+                    if let Err(e) = window.emit("evt_groups_changed", groups.list()) {
+                        log::error!("evt_groups_changed err {e:#?}");
+                    }
+                }
+                return
+            },
+            Message::Disconnect { ref reason } => {
+                // They have turned us away, so leave them be for a while
+                log::info!("Disconnected by {}: {reason}", peer.peer_addr);
+                let disconnect = PeerDisconnect {
+                    addr: peer.peer_addr.to_string(),
+                    name: peer.peer_profile.as_ref().map(|p| p.name.clone()),
+                    uid: peer.uid(),
+                    reason: reason.clone(),
+                };
+                if let Err(e) = window.emit("evt_peer_disconnected", disconnect) {
+                    log::error!("evt_peer_disconnected err {e:#?}");
+                }
 
-    send_msgs_to_all_peers(vec![msg], &window);
-}
+                let uid = peer.held_uid();
+                let dialed = peer.dialed.then_some(peer.peer_addr);
+                self.peers.remove(&id);
+                if let Some(uid) = uid {
+                    self.refused_by.insert(uid, Instant::now() + Duration::from_secs(REFUSED_TIME));
+                    self.forget_uid(uid);
+                }
+                if let Some(addr) = dialed {
+                    self.note_refused_at(addr);
+                }
+                return
+            },
+            Message::FilePart { mid, offset, ref data } => {
+                if let Some(uid) = peer.uid() {
+                    state.files.lock().unwrap().add_part(uid, mid, offset, data, &window);
+                }
+                return
+            },
+            Message::Text(_) | Message::Image(_) | Message::File(_) | Message::Audio(_) |
+            Message::DirectText(_) | Message::DirectImage(_) | Message::GroupText(_) | Message::GroupImage(_) => {
+                // Several peers can resend us the same message after a
+                // drop, and we may have already gotten it before the drop
+                let mid = rec_msg.chat_data().map(|data| data.mid);
+                let already_have = state.msg_history.lock().unwrap().iter().any(|msg| {
+                    msg.chat_data().map(|old| old.mid) == mid
+                });
+                if already_have {
+                    log::trace!("Ignoring duplicate message {mid:?} from {}", peer.peer_addr);
+                    return
+                }
+            },
+            _ => {},
+        }
 
-#[tauri::command]
-pub fn cmd_send_img(img: &str, state: State<AppState>, window: tauri::Window) {
-    let (name, uid) = {
-        let profile = state.profile.lock().unwrap();
-        (profile.name.clone(), profile.uid)
-    };
+        log::info!("Received {} message from {}", rec_msg.get_type_str(), peer.peer_addr);
 
-    let msg = Message::Image(MessageData::new(
-        name,
-        uid,
-        gen_rand_id(),
-        get_curr_time(),
-        parse_img_str(img),
-    ));
+        // add to msg history
+        {
+            let mut msg_history = state.msg_history.lock().unwrap();
+            msg_history.push(rec_msg.clone());
+        }
+
+        match &rec_msg {
+            Message::Hello(hello) => {
+                // If this is a greeting from a new peer/user, we need to record their
+                // information so we can poll it later
+                let data = &hello.data;
+                let rec_profile = Profile {
+                    name: data.name.clone(),
+                    uid: data.uid,
+                    join_time: data.timestamp,
+                    pic: data.payload.clone(),
+                    public_key: hello.public_key.clone(),
+                };
+                // Their key was cleared above unless they proved it's theirs
+                let key = (data.uid != own_uid && !hello.public_key.is_empty())
+                    .then(|| trust::check_hello(&rec_profile, &state, &window));
+                log::info!("Adding {} to known users.", rec_profile.name);
+                state.known_users.lock().unwrap().add_user(rec_profile.clone(), key, &window);
+
+                // also add profile information to the connection
+                peer.peer_profile = Some(rec_profile);
 
-    send_msgs_to_all_peers(vec![msg], &window);
-}
\ No newline at end of file
+                // Now that they have checked out, it's our turn if they dialed us
+                if !peer.dialed {
+                    say_hello(&state, peer);
+                }
+
+                if peer.is_self {
+                    // This is the other end of the connection we dialed
+                    // to ourselves, which we only ever read from
+                    peer.stream_type = TcpStreamType::Read;
+                }
+
+                // and start talking to them in the best format we both understand
+                peer.wire_format = WireFormat::negotiate(hello);
+                peer.queue(Outgoing::Format(peer.wire_format));
+                peer.queue(Outgoing::Chunks(hello.has_capability(CAP_CHUNKS)));
+                peer.capabilities = hello.capabilities.clone();
+                peer.protocol_version = hello.protocol_version;
+                if hello.listen_port != 0 {
+                    let mut listen_addr = peer.peer_addr;
+                    listen_addr.set_port(hello.listen_port);
+                    peer.peer_listen_addr = Some(listen_addr);
+                }
+                if hello.has_capability(CAP_RESUME) && peer.stream_type == TcpStreamType::Both {
+                    // Let them know where we are, so they can fill us in
+                    // on anything we missed while we weren't connected
+                    let seen = make_sync_watermark(&state.msg_history.lock().unwrap());
+                    peer.send(Message::Sync { seen });
+                }
+                if peer.stream_type == TcpStreamType::Both {
+                    // Pick up any downloads from them that a drop cut off
+                    for request in state.files.lock().unwrap().resume_requests(data.uid) {
+                        peer.send(request);
+                    }
+                    // and catch them up on any groups we are both in
+                    let shared = state.groups.lock().unwrap().shared_with(data.uid);
+                    let profile = state.profile.lock().unwrap().clone();
+                    let identity = state.identity.lock().unwrap();
+                    for group in shared {
+                        peer.send(groups::make_update_msg(&profile, &identity, &group));
+                    }
+                }
+                log::info!(
+                    "Peer {} speaks protocol v{}, using {:?} wire format",
+                    peer.peer_addr, hello.protocol_version, peer.wire_format
+                );
+
+                self.p2p_uids.insert(data.uid);
+                self.close_duplicate_peers(own_uid);
+                self.sync_room_members(own_uid);
+            },
+            Message::Goodbye(_) => {
+                // This peer is going to be shutting down soon, so we should
+                // clean up their connection status
+                log::info!("Goodbye received from {}", peer.peer_addr);
+                if let Some(uid) = self.peers.remove(&id).and_then(|peer| peer.uid()) {
+                    self.forget_uid(uid);
+                }
+            },
+            // Send back Ack, unless it's from ourselves
+            Message::File(file) if file.data.uid != own_uid => {
+                state.files.lock().unwrap().add_offer(*file.clone(), &window);
+                let ack_msg = Message::Ack {
+                    uid: own_uid,
+                    mid: file.data.mid,
+                };
+                self.send_to_peers(&[ack_msg], |_| true);
+            },
+            // Only whoever sent a private message finds out we got it
+            Message::DirectText(DirectData { data, .. }) |
+            Message::DirectImage(DirectData { data, .. }) if data.uid != own_uid => {
+                let ack_msg = Message::Ack {
+                    uid: own_uid,
+                    mid: data.mid,
+                };
+                let sender = data.uid;
+                self.send_to_peers(&[ack_msg], |peer| peer.uid() == Some(sender));
+            },
+            // and only the members of a group find out who got its messages
+            Message::GroupText(GroupMsgData { data, gid }) |
+            Message::GroupImage(GroupMsgData { data, gid }) if data.uid != own_uid => {
+                let ack_msg = Message::Ack {
+                    uid: own_uid,
+                    mid: data.mid,
+                };
+                let groups = state.groups.lock().unwrap();
+                self.send_to_peers(&[ack_msg], |peer| peer.uid().map_or(false, |uid| groups.is_member(*gid, uid)));
+            },
+            Message::Image(data) |
+            Message::Text(data) |
+            Message::Audio(AudioData { data, .. }) if data.uid != own_uid => {
+                let ack_msg = Message::Ack {
+                    uid: own_uid,
+                    mid: data.mid,
+                };
+                self.send_to_peers(&[ack_msg], |_| true);
+            },
+            // don't care about hello or broadcast msg,
+            // also, more importantly, don't want to ack acks
+            // because that would create an infinite loop of
+            // packets bouncing across the network
+            _ => {},
+        }
+
+        send_msg_to_frontend(&rec_msg, &window);
+    }
+
+    // Builds that can encrypt never talk in plaintext, so if one is, someone
+    // in between has stripped the handshake to read or change what is sent
+    fn downgrade_refusal(&self, id: PeerId, hello: &HelloData) -> Option<String> {
+        let plaintext = self.peers.get(&id).map_or(false, |peer| peer.handshake_hash.is_none());
+        if plaintext && (hello.protocol_version >= NOISE_VERSION || self.noise_uids.contains(&hello.data.uid)) {
+            return Some("You can encrypt, but this connection isn't encrypted".to_owned());
+        }
+        None
+    }
+
+    // Why we won't let a peer that just said Hello into our room, if we won't.
+    // Our own connection is checked like any other, so nobody gets in by
+    // claiming to be us, but proves it knows the passphrase with our Noise key
+    fn room_refusal(&self, id: PeerId, hello: &HelloData, own_uid: u32) -> Option<String> {
+        let state: State<AppState> = self.window.state();
+
+        let room = match self.own_room() {
+            Some(room) => room,
+            None => return Some("Not in a room".to_owned()),
+        };
+        if hello.room != room {
+            return Some(format!("In room {room}, not room {}", hello.room));
+        }
+
+        // Their proof was made from their side of the connection. Our own
+        // connection skips the exchange, see handle_established.
+        let locked = state.rooms.lock().unwrap().current_key().is_some();
+        match self.peers.get(&id) {
+            Some(peer) if locked && !peer.is_self => match (&peer.handshake_hash, &peer.room_secret) {
+                (None, _) => return Some(format!("Room {room} is locked, and needs an encrypted connection")),
+                (Some(_), None) => return Some(format!("Room {room} is locked")),
+                (Some(handshake_hash), Some(secret)) => {
+                    if !rooms::check_room_proof(secret, handshake_hash, !peer.dialed, &hello.room_proof) {
+                        return Some(format!("Wrong passphrase for room {room}"));
+                    }
+                },
+            },
+            _ => {},
+        }
+
+        let members = self.room_members(own_uid);
+        let capacity = state.rooms.lock().unwrap().capacity() as usize;
+        if !members.contains(&hello.data.uid) && members.len() >= capacity {
+            return Some(format!("Room {room} is full"));
+        }
+        None
+    }
+
+    // Closes the connection to a peer that just said Hello, telling them why
+    // if they understand
+    fn refuse_peer(&mut self, id: PeerId, hello: &HelloData, reason: String) {
+        if let Some(peer) = self.peers.get_mut(&id) {
+            log::info!("{} at {} said Hello", hello.data.name, peer.peer_addr);
+            peer.capabilities = hello.capabilities.clone();
+        }
+        self.refuse_connection(id, reason);
+    }
+
+    // Closes a connection, telling them why if their capabilities say they
+    // understand
+    fn refuse_connection(&mut self, id: PeerId, reason: String) {
+        let peer = match self.peers.remove(&id) {
+            Some(peer) => peer,
+            None => return,
+        };
+        log::info!("Turning away {}: {reason}", peer.peer_addr);
+        if peer.dialed {
+            self.note_refused_at(peer.peer_addr);
+        }
+
+        // The connection closes once the writer has sent it
+        peer.queue_in_background(vec![Outgoing::Msg(Message::Disconnect { reason })]);
+        if let Some(uid) = peer.held_uid() {
+            self.forget_uid(uid);
+        }
+    }
+
+    // uids of everyone in our room we have said Hello to, including us
+    fn room_members(&self, own_uid: u32) -> Vec<u32> {
+        let mut members: Vec<u32> = self.peers.values()
+            .filter_map(|peer| peer.peer_profile.as_ref().map(|p| p.uid))
+            .chain(std::iter::once(own_uid))
+            .collect();
+        members.sort();
+        members.dedup();
+        members
+    }
+
+    fn sync_room_members(&self, own_uid: u32) {
+        let state: State<AppState> = self.window.state();
+        let members = self.room_members(own_uid);
+        state.rooms.lock().unwrap().set_members(members);
+    }
+
+    // Says goodbye to everyone in the room we are in and closes our
+    // connections with them, then moves us into room. Returns its history.
+    fn change_room(&mut self, room: Option<String>, goodbye: Message) -> Vec<Message> {
+        let own_uid = self.own_uid();
+
+        // Connections that haven't said Hello yet are checked against the new
+        // room once they do
+        let leaving: Vec<PeerId> = self.peers.iter()
+            .filter(|(_, peer)| peer.peer_profile.as_ref().map_or(false, |p| p.uid != own_uid))
+            .map(|(id, _)| *id)
+            .collect();
+        // Nobody waits for these, unlike on shutdown, since we aren't going anywhere
+        let (done, _) = std::sync::mpsc::channel();
+        for id in leaving {
+            if let Some(peer) = self.peers.remove(&id) {
+                if peer.stream_type == TcpStreamType::Both {
+                    // The writer sends whatever is still queued, then the
+                    // Goodbye, and only then closes
+                    peer.queue_in_background(vec![Outgoing::Close(Some(Box::new(goodbye.clone())), done.clone())]);
+                }
+                if let Some(uid) = peer.uid() {
+                    self.forget_uid(uid);
+                }
+            }
+        }
+        self.reconnects.clear();
+        self.refused_by.clear();
+        self.refused_at.clear();
+
+        let state: State<AppState> = self.window.state();
+        // We get advertised with the new room the next time mDNS is polled
+        state.connection.mdns.stop();
+
+        let mut msg_history = state.msg_history.lock().unwrap();
+        state.rooms.lock().unwrap().switch(room, &mut msg_history);
+        msg_history.clone()
+    }
+
+    fn handle_progress(&self, id: PeerId, progress: Progress) {
+        let peer = match self.peers.get(&id) {
+            Some(peer) => peer,
+            None => return,
+        };
+        if peer.stream_type != TcpStreamType::Both {
+            return; // we already show our own messages as sent
+        }
+
+        let progress = TransferProgress {
+            transfer_id: progress.transfer_id,
+            uid: peer.uid(),
+            sending: progress.sending,
+            bytes: progress.bytes,
+            total: progress.total,
+        };
+        if let Err(e) = self.window.emit("evt_transfer_progress", progress) {
+            log::error!("evt_transfer_progress err {e:#?}");
+        }
+    }
+
+    fn handle_closed(&mut self, id: PeerId, err: PeerError) {
+        let peer = match self.peers.remove(&id) {
+            Some(peer) => peer,
+            None => return, // we closed it ourselves
+        };
+
+        match err {
+            PeerError::Decode(e) => {
+                // They are misbehaving rather than gone, so don't try to get them back.
+                // The stream is no longer in a state we can trust (we don't know where
+                // the next frame starts), so cut the peer off entirely
+                let dropped_msg = self.cut_off_peer(&peer, &e);
+                self.push_dropped_msg(dropped_msg);
+            },
+            PeerError::Closed => {
+                log::warn!("Connection closed by {}. Manufacturing drop message.", peer.peer_addr);
+                self.lose_peer(&peer);
+            },
+            PeerError::Io(e) => {
+                log::warn!("Stream at {} no longer valid: {e}. Manufacturing drop message.", peer.peer_addr);
+                self.lose_peer(&peer);
+            },
+            PeerError::Refused(reason) => {
+                // Nothing went wrong, so there is no drop to show or reconnect to try
+                log::info!("Refused connection with {}: {reason}", peer.peer_addr);
+                if peer.dialed {
+                    self.note_refused_at(peer.peer_addr);
+                }
+                let disconnect = PeerDisconnect {
+                    addr: peer.peer_addr.to_string(),
+                    name: None,
+                    uid: peer.uid(),
+                    reason,
+                };
+                if let Err(e) = self.window.emit("evt_peer_disconnected", disconnect) {
+                    log::error!("evt_peer_disconnected err {e:#?}");
+                }
+            },
+        }
+
+        if let Some(uid) = peer.held_uid() {
+            self.forget_uid(uid);
+        }
+    }
+
+    // For peers we lost without a Goodbye. Tells the frontend, and tries to
+    // get them back.
+    fn lose_peer(&mut self, peer: &Peer) {
+        let own_uid = self.own_uid();
+        if peer.uid() == Some(own_uid) {
+            return;
+        }
+
+        // if we know who the connection was from, then manufacture a dropped msg
+        // and insert into the frontend so that it can display the connection was dropped
+        self.push_dropped_msg(peer.peer_profile.as_ref().map(make_dropped_msg));
+        self.schedule_reconnect(peer, own_uid);
+    }
+
+    fn push_dropped_msg(&self, dropped_msg: Option<Message>) {
+        let state: State<AppState> = self.window.state();
+
+        if let Some(dropped_msg) = dropped_msg {
+            send_msg_to_frontend(&dropped_msg, &self.window);
+            state.msg_history.lock().unwrap().push(dropped_msg);
+        }
+    }
+
+    // Logs, counts and quarantines a peer that sent us a frame we won't accept,
+    // and lets the frontend know. Returns the Dropped message to display if we
+    // knew who the peer was. Caller is responsible for removing the connection.
+    fn cut_off_peer(&mut self, peer: &Peer, err: &DecodeError) -> Option<Message> {
+        let offender = match (peer.uid(), peer.dialed) {
+            // Quarantining ourselves would refuse our own Hello until it expired
+            _ if peer.is_self => None,
+            (Some(uid), _) => Some(Offender::Uid(uid)),
+            (None, true) => Some(Offender::Addr(peer.peer_addr)),
+            // All we know is an ephemeral port, which they won't use again
+            (None, false) => None,
+        };
+        match offender {
+            Some(offender) => {
+                let count = self.record_decode_failure(offender);
+                log::warn!("Bad frame from {} ({count} total): {err}. Quarantining {offender}.", peer.peer_addr);
+                self.quarantine(offender);
+            },
+            None => log::warn!("Bad frame from {}: {err}. Closing connection.", peer.peer_addr),
+        }
+
+        let disconnect = PeerDisconnect {
+            addr: peer.peer_addr.to_string(),
+            name: peer.peer_profile.as_ref().map(|p| p.name.clone()),
+            uid: peer.peer_profile.as_ref().map(|p| p.uid),
+            reason: err.to_string(),
+        };
+        if let Err(e) = self.window.emit("evt_peer_disconnected", disconnect) {
+            log::error!("evt_peer_disconnected err {e:#?}");
+        }
+
+        peer.peer_profile.as_ref().map(make_dropped_msg)
+    }
+
+    // Returns the total number of bad frames we have seen from them
+    fn record_decode_failure(&mut self, offender: Offender) -> u32 {
+        let count = self.decode_failures.entry(offender).or_insert(0);
+        *count += 1;
+        *count
+    }
+
+    fn quarantine(&mut self, offender: Offender) {
+        let until = Instant::now() + Duration::from_secs(QUARANTINE_TIME);
+        self.quarantined.insert(offender, until);
+    }
+
+    fn is_quarantined(&mut self, offender: Offender) -> bool {
+        match self.quarantined.get(&offender) {
+            Some(until) if *until > Instant::now() => true,
+            Some(_) => {
+                log::info!("Quarantine expired for {offender}");
+                self.quarantined.remove(&offender);
+                false
+            },
+            None => false,
+        }
+    }
+
+    fn note_refused_at(&mut self, addr: SocketAddr) {
+        self.refused_at.insert(addr, Instant::now() + Duration::from_secs(REFUSED_TIME));
+    }
+
+    fn is_refused_at(&mut self, addr: &SocketAddr) -> bool {
+        match self.refused_at.get(addr) {
+            Some(until) if *until > Instant::now() => true,
+            Some(_) => {
+                self.refused_at.remove(addr);
+                false
+            },
+            None => false,
+        }
+    }
+
+    fn is_refused_by(&mut self, uid: u32) -> bool {
+        match self.refused_by.get(&uid) {
+            Some(until) if *until > Instant::now() => true,
+            Some(_) => {
+                self.refused_by.remove(&uid);
+                false
+            },
+            None => false,
+        }
+    }
+
+    fn is_room_full(&self, own_uid: u32) -> bool {
+        let state: State<AppState> = self.window.state();
+        let capacity = state.rooms.lock().unwrap().capacity() as usize;
+        self.room_members(own_uid).len() >= capacity
+    }
+
+    // Called after connections are removed. Forgets the uid if it no longer
+    // has any connection left, so it can be dialed again.
+    fn forget_uid(&mut self, uid: u32) {
+        if !self.peers.values().any(|peer| peer.held_uid() == Some(uid)) {
+            log::trace!("Removing uid={uid} from set");
+            self.p2p_uids.remove(&uid);
+        }
+        self.sync_room_members(self.own_uid());
+    }
+
+    // Two hosts can end up with two connections between them, e.g. if they
+    // dial each other at the same time, or a reconnect races with discovery.
+    // Both sides keep the same one: the one dialed by the greater uid, and the
+    // oldest of those if there are still several. The others are closed quietly,
+    // since we are still talking to the peer.
+    fn close_duplicate_peers(&mut self, own_uid: u32) {
+        let mut by_uid: HashMap<u32, Vec<(PeerId, bool)>> = HashMap::new();
+        for (id, peer) in self.peers.iter().filter(|(_, peer)| peer.stream_type == TcpStreamType::Both) {
+            if let Some(profile) = &peer.peer_profile {
+                by_uid.entry(profile.uid).or_default().push((*id, peer.dialed));
+            }
+        }
+
+        for (peer_uid, mut conns) in by_uid {
+            if conns.len() < 2 {
+                continue;
+            }
+            conns.sort();
+
+            let we_dial = own_uid > peer_uid;
+            let keep = conns.iter().find(|(_, dialed)| *dialed == we_dial).unwrap_or(&conns[0]).0;
+            for (id, _) in conns {
+                if id == keep {
+                    continue;
+                }
+                if let Some(peer) = self.peers.remove(&id) {
+                    log::info!("Closing duplicate connection to uid={peer_uid} at {}", peer.peer_addr);
+                }
+            }
+        }
+    }
+
+    // Sends msgs to every connection we can write to that matches filter
+    fn send_to_peers(&self, msgs: &[Message], filter: impl Fn(&Peer) -> bool) {
+        let peers = self.peers.values()
+            .filter(|peer| peer.stream_type != TcpStreamType::Read && peer.accepted() && filter(peer));
+        for peer in peers {
+            for msg in msgs {
+                peer.send(msg.clone());
+            }
+        }
+    }
+
+    // Connecting never holds up the network task, the result comes back as a
+    // NetEvent::Dialed. The caller is responsible for keeping p2p_uids up to date.
+    fn dial(
+        &self,
+        saddr: SocketAddr,
+        expected_uid: Option<u32>,
+        handshake: Handshake,
+        reply: Option<oneshot::Sender<Result<(), String>>>,
+    ) {
+        let net_events = self.net_events.clone();
+        async_runtime::spawn(async move {
+            let timeout = Duration::from_secs(CONNECT_TIMEOUT);
+            let result = match tokio::time::timeout(timeout, connect(saddr)).await {
+                Ok(result) => result,
+                Err(_) => Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "connection timed out")),
+            };
+            let _ = net_events.send(NetEvent::Dialed { saddr, expected_uid, handshake, result, reply });
+        });
+    }
+
+    fn housekeeping(&mut self) {
+        if self.active {
+            self.connect_to_self();
+            // Until we are in a room there is nobody to find
+            if let Some(room) = self.own_room() {
+                self.send_broadcast(&room);
+                self.listen_for_mdns(&room);
+                self.dial_static_peers();
+                self.redial_dropped_peers();
+            }
+        }
+        // Broadcasts are listened for from the start, so the user can see
+        // who is in each room before joining one
+        self.listen_for_broadcasts();
+        self.expire_nearby();
+        self.check_heartbeats();
+        self.close_unaccepted_peers();
+    }
+
+    // Heartbeats only start once a peer has said Hello, so this is what stops
+    // anyone holding a connection open without ever joining our room
+    fn close_unaccepted_peers(&mut self) {
+        let timeout = Duration::from_secs(HELLO_TIMEOUT);
+        let late: Vec<PeerId> = self.peers.iter()
+            .filter(|(_, peer)| !peer.accepted() && peer.connected_at.elapsed() > timeout)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in late {
+            if let Some(peer) = self.peers.remove(&id) {
+                log::info!("{} hasn't said Hello in {HELLO_TIMEOUT}s, closing connection", peer.peer_addr);
+                if let Some(uid) = peer.held_uid() {
+                    self.forget_uid(uid);
+                }
+            }
+        }
+    }
+
+    fn check_heartbeats(&mut self) {
+        let state: State<AppState> = self.window.state();
+        let config = state.connection.heartbeat();
+
+        // Only peers that answer our pings can be expected to keep talking.
+        // Our own read stream never gets pinged, since it isn't Both.
+        let timeout = Duration::from_secs(config.timeout_secs as u64);
+        let silent: Vec<PeerId> = self.peers.iter()
+            .filter(|(_, peer)| peer.has_capability(CAP_HEARTBEAT) && peer.stream_type == TcpStreamType::Both)
+            .filter(|(_, peer)| peer.last_heard.elapsed() > timeout)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in silent {
+            if let Some(peer) = self.peers.remove(&id) {
+                log::warn!(
+                    "Haven't heard from {} in {}s. Manufacturing drop message.",
+                    peer.peer_addr, peer.last_heard.elapsed().as_secs()
+                );
+                self.lose_peer(&peer);
+                if let Some(uid) = peer.uid() {
+                    self.forget_uid(uid);
+                }
+            }
+        }
+
+        if self.last_heartbeat.elapsed() < Duration::from_secs(config.interval_secs as u64) {
+            return;
+        }
+        self.last_heartbeat = Instant::now();
+
+        // Older peers would choke on a Ping, but it is never sent to them
+        self.send_to_peers(&[Message::Ping(gen_rand_id())], |peer| peer.stream_type == TcpStreamType::Both);
+    }
+
+    // We see our own messages by connecting to our own listener and reading
+    // them back, the same as everyone else's. We know the end we dial is us
+    // because we dial it with our own uid, and the end we accept because it
+    // has our Noise key, so this works however many instances share the machine.
+    fn connect_to_self(&mut self) {
+        let state: State<AppState> = self.window.state();
+
+        let uid = self.own_uid();
+        if self.p2p_uids.insert(uid) {
+            let self_saddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), state.connection.p2p_port);
+            self.dial(self_saddr, Some(uid), Handshake::Initiate, None);
+        }
+    }
+
+    fn send_broadcast(&self, room: &str) {
+        let state: State<AppState> = self.window.state();
+
+        let uid = self.own_uid();
+        let members = self.room_members(uid).len() as u32;
+        let locked = state.rooms.lock().unwrap().current_key().is_some();
+        let data = BroadcastData::new(uid, state.connection.p2p_port, room.to_owned(), members, locked);
+        let msg = [Message::legacy_broadcast(uid), Message::Broadcast(data).to_network(WireFormat::Json)].concat();
+
+        state.connection.discovery.announce(&msg, &state.connection.excluded_interfaces());
+    }
+
+    fn listen_for_broadcasts(&mut self) {
+        let state: State<AppState> = self.window.state();
+
+        state.connection.discovery.join_groups(&state.connection.excluded_interfaces());
+
+        // Every host sends a broadcast out of each of its interfaces, so drain
+        // everything that has arrived instead of one datagram at a time
+        for (buf, rec_saddr) in state.connection.discovery.recv_all() {
+            self.handle_broadcast(&buf, rec_saddr);
+        }
+    }
+
+    fn listen_for_mdns(&mut self, room: &str) {
+        let state: State<AppState> = self.window.state();
+
+        if !state.connection.discovery.config().mdns {
+            return;
+        }
+
+        let profile = state.profile.lock().unwrap().clone();
+        let locked = state.rooms.lock().unwrap().current_key().is_some();
+        let peers = state.connection.mdns.poll(&profile, state.connection.p2p_port, room, locked);
+        for (peer_data, peer_saddr) in peers {
+            log::trace!("Resolved uid={} at {peer_saddr} over mDNS", peer_data.uid);
+            self.connect_to_discovered_peer(peer_data, peer_saddr);
+        }
+    }
+
+    fn handle_broadcast(&mut self, buf: &[u8], rec_saddr: SocketAddr) {
+        let state: State<AppState> = self.window.state();
+
+        // Our datagrams start with a legacy broadcast for older builds, and have
+        // the full one after it, so go by the last one in there
+        let mut rest = buf;
+        let mut rec_data = None;
+        while !rest.is_empty() {
+            match Message::from_network(rest, &state.connection.limits()) {
+                Ok(Message::Broadcast(data)) => rec_data = Some(data),
+                Ok(_) => {
+                    log::warn!("Received non-broadcast msg on the udp socket: {buf:?}");
+                    return;
+                },
+                Err(e) => {
+                    // Something else on the LAN is using our port, nothing we can do
+                    // about it but ignore it
+                    log::debug!("Ignoring undecodable datagram from {rec_saddr}: {e}");
+                    return;
+                },
+            }
+            rest = &rest[Message::frame_len(rest)..];
+        }
+
+        if let Some(data) = rec_data {
+            if self.is_quarantined(Offender::Uid(data.uid)) {
+                return;
+            }
+            self.note_nearby(&data);
+            if self.active {
+                self.connect_to_discovered_peer(data, rec_saddr);
+            }
+        }
+    }
+
+    // Keeps track of who is in which room, for the room browser
+    fn note_nearby(&self, data: &BroadcastData) {
+        if data.uid == self.own_uid() {
+            return;
+        }
+
+        let state: State<AppState> = self.window.state();
+        let mut rooms = state.rooms.lock().unwrap();
+        if rooms.heard(data.uid, &data.room, data.members, data.locked) {
+            emit_rooms_changed(&rooms.nearby(), &self.window);
+        }
+    }
+
+    fn expire_nearby(&self) {
+        let state: State<AppState> = self.window.state();
+        let mut rooms = state.rooms.lock().unwrap();
+        if rooms.expire_nearby() {
+            emit_rooms_changed(&rooms.nearby(), &self.window);
+        }
+    }
+
+    // Called for every peer we hear about, however we heard about it.
+    // rec_saddr is the address we heard from, the port is ignored in favor of
+    // the one the peer advertised.
+    fn connect_to_discovered_peer(&mut self, rec_data: BroadcastData, rec_saddr: SocketAddr) {
+        if rec_data.protocol_version >= NOISE_VERSION {
+            self.noise_uids.insert(rec_data.uid);
+        }
+
+        let own_uid = self.own_uid();
+        if rec_data.uid == own_uid {
+            // We hear ourselves on every interface and transport we announce on,
+            // but connect to ourselves separately, see connect_to_self
+            return
+        }
+        if rec_data.uid > own_uid {
+            log::trace!(
+                "Received broadcast from uid={}. Their uid is greater, so waiting for them to establish a connection.",
+                rec_data.uid
+            );
+            return
+        }
+        if self.own_room().as_deref() != Some(rec_data.room.as_str()) {
+            log::trace!("Ignoring broadcast from uid={} in room {}", rec_data.uid, rec_data.room);
+            return
+        }
+        if self.is_refused_by(rec_data.uid) {
+            log::trace!("uid={} turned us away recently, so ignoring their broadcast", rec_data.uid);
+            return
+        }
+        if self.is_room_full(own_uid) {
+            log::trace!("Our room is full, so ignoring broadcast from uid={}", rec_data.uid);
+            return
+        }
+        let locked = self.is_room_locked();
+        if rec_data.locked != locked {
+            // One of us would only turn the other away
+            log::trace!("Ignoring broadcast from uid={}, whose room is {}locked", rec_data.uid, if locked { "not " } else { "" });
+            return
+        }
+        if rec_data.protocol_version < NOISE_VERSION && self.is_encryption_required() {
+            log::trace!("uid={} can't encrypt, which we require, so ignoring their broadcast", rec_data.uid);
+            return
+        }
+
+        // The same peer can be heard over several transports (e.g. IPv4 broadcast
+        // and IPv6 multicast) with different addresses, but we only want one connection
+        if !self.p2p_uids.insert(rec_data.uid) {
+            log::trace!("Already connected to uid={}, so ignoring broadcast from {rec_saddr}", rec_data.uid);
+            return;
+        }
+
+        log::trace!(
+            "New broadcast from uid={} at {rec_saddr} (protocol v{}), so attempting to establish connection.",
+            rec_data.uid, rec_data.protocol_version
+        );
+
+        // keep the scope id of IPv6 link-local addresses, otherwise the OS
+        // doesn't know which interface to connect out of
+        let mut tcp_saddr = rec_saddr;
+        tcp_saddr.set_port(rec_data.port);
+        self.dial(tcp_saddr, Some(rec_data.uid), Handshake::for_version(rec_data.protocol_version), None);
+    }
+
+    fn is_room_locked(&self) -> bool {
+        let state: State<AppState> = self.window.state();
+        let locked = state.rooms.lock().unwrap().current_key().is_some();
+        locked
+    }
+
+    fn is_encryption_required(&self) -> bool {
+        let state: State<AppState> = self.window.state();
+        let required = state.settings.lock().unwrap().encryption_required;
+        required
+    }
+
+    fn is_own_listen_addr(&self, saddr: &SocketAddr) -> bool {
+        let state: State<AppState> = self.window.state();
+
+        saddr.port() == state.connection.p2p_port && (
+            saddr.ip().is_loopback() ||
+                interfaces::get_local_interfaces().iter().any(|iface| IpAddr::V4(iface.ip) == saddr.ip())
+        )
+    }
+
+    // Connects to a peer the user gave us the address of, unless we already
+    // have a connection with them. These don't follow the greater uid rule,
+    // since we don't know their uid until they say Hello. Resolving the
+    // address can take a while, so it happens off the network task.
+    fn connect_to_static_peer(&self, addr: String, reply: Option<oneshot::Sender<Result<(), String>>>) {
+        let net_events = self.net_events.clone();
+        async_runtime::spawn(async move {
+            let result = resolve_peer_addr(&addr).await;
+            let _ = net_events.send(NetEvent::Resolved { addr, result, reply });
+        });
+    }
+
+    // Returns where to dial a resolved static peer, or None if we are already
+    // connected to them
+    fn check_static_peer(&mut self, addr: &str, tcp_saddr: SocketAddr) -> Result<Option<SocketAddr>, String> {
+        if self.is_own_listen_addr(&tcp_saddr) {
+            return Err(format!("{addr} is this instance of ectochat"));
+        }
+
+        if self.is_quarantined(Offender::Addr(tcp_saddr)) {
+            return Err(format!("{addr} is quarantined for sending bad messages"));
+        }
+
+        if self.is_refused_at(&tcp_saddr) {
+            return Err(format!("{addr} was turned away recently, so not dialing it again yet"));
+        }
+
+        // We don't know their uid yet, so go by where they are listening. If we
+        // race with them dialing us, the duplicate gets closed after Hello.
+        let already_connected = self.peers.values().any(|peer| {
+            peer.peer_listen_addr == Some(tcp_saddr) || (peer.dialed && peer.peer_addr == tcp_saddr)
+        });
+        if already_connected {
+            log::trace!("Already connected to static peer {addr}");
+            return Ok(None);
+        }
+
+        log::info!("Connecting to static peer {addr} at {tcp_saddr}");
+        Ok(Some(tcp_saddr))
+    }
+
+    // Static peers get redialed every so often, so they come back after they
+    // drop or if they weren't running the first time we tried
+    fn dial_static_peers(&mut self) {
+        let state: State<AppState> = self.window.state();
+
+        if let Some(last) = self.last_static_dial {
+            if last.elapsed() < Duration::from_secs(STATIC_PEER_REDIAL_TIME) {
+                return;
+            }
+        }
+        self.last_static_dial = Some(Instant::now());
+
+        let static_peers = state.settings.lock().unwrap().static_peers.clone();
+        for addr in static_peers {
+            self.connect_to_static_peer(addr, None);
+        }
+    }
+
+    // Remembers to redial a peer we just lost. Only the side that would have
+    // dialed them in the first place does this (or else both sides would dial
+    // each other at once), and only if we know where they are listening.
+    fn schedule_reconnect(&mut self, peer: &Peer, own_uid: u32) {
+        let (profile, addr) = match (&peer.peer_profile, peer.peer_listen_addr) {
+            (Some(profile), Some(addr)) => (profile, addr),
+            _ => return,
+        };
+        if profile.uid >= own_uid {
+            return;
+        }
+
+        log::info!("Will try to reconnect to {} at {addr}", profile.name);
+        self.reconnects.insert(profile.uid, Reconnect {
+            addr,
+            handshake: Handshake::for_version(peer.protocol_version),
+            attempts: 0,
+            next_attempt: Instant::now() + reconnect_backoff(0),
+        });
+    }
+
+    fn reconnect_failed(&mut self, uid: u32, err: &std::io::Error) {
+        if let Some(reconnect) = self.reconnects.get_mut(&uid) {
+            reconnect.attempts += 1;
+            if reconnect.attempts >= MAX_RECONNECT_ATTEMPTS {
+                log::info!("Giving up reconnecting to uid={uid} at {}: {err}", reconnect.addr);
+                self.reconnects.remove(&uid);
+            } else {
+                log::debug!("Could not reconnect to uid={uid} at {}, attempt {}: {err}", reconnect.addr, reconnect.attempts);
+                reconnect.next_attempt = Instant::now() + reconnect_backoff(reconnect.attempts);
+            }
+        }
+    }
+
+    // Tries to get back peers we lost without a Goodbye, backing off between
+    // attempts. Whoever reconnects first (us, them, or discovery) wins.
+    fn redial_dropped_peers(&mut self) {
+        let now = Instant::now();
+        let due: Vec<(u32, SocketAddr, Handshake)> = self.reconnects.iter()
+            .filter(|(_, reconnect)| reconnect.next_attempt <= now)
+            .map(|(uid, reconnect)| (*uid, reconnect.addr, reconnect.handshake))
+            .collect();
+
+        for (uid, addr, handshake) in due {
+            let already_connected = self.peers.values().any(|peer| peer.uid() == Some(uid));
+            if already_connected || self.is_quarantined(Offender::Uid(uid)) {
+                self.reconnects.remove(&uid);
+                continue;
+            }
+
+            if !self.p2p_uids.insert(uid) {
+                // something else is already dialing them, check back later
+                continue;
+            }
+
+            log::info!("Trying to reconnect to uid={uid} at {addr}");
+            // don't try again until this attempt has had a chance to finish
+            if let Some(reconnect) = self.reconnects.get_mut(&uid) {
+                reconnect.next_attempt = now + reconnect_backoff(reconnect.attempts);
+            }
+            self.dial(addr, Some(uid), handshake, None);
+        }
+    }
+}
+
+fn emit_rooms_changed(nearby: &[NearbyRoom], window: &tauri::Window) {
+    if let Err(e) = window.emit("evt_rooms_changed", nearby) {
+        log::error!("evt_rooms_changed err {e:#?}");
+    }
+}
+
+#[tauri::command]
+pub fn cmd_get_message_limits(state: State<AppState>) -> MessageLimits {
+    state.connection.limits()
+}
+
+#[tauri::command]
+pub fn cmd_set_message_limits(limits: MessageLimits, state: State<AppState>) {
+    log::info!("Updating message limits: {limits:?}");
+    *state.connection.limits.lock().unwrap() = limits;
+}
+
+#[tauri::command]
+pub fn cmd_get_heartbeat_config(state: State<AppState>) -> HeartbeatConfig {
+    state.connection.heartbeat()
+}
+
+#[tauri::command]
+pub fn cmd_set_heartbeat_config(config: HeartbeatConfig, state: State<AppState>) -> Result<(), String> {
+    if config.interval_secs == 0 || config.timeout_secs <= config.interval_secs {
+        return Err("Heartbeat timeout must be longer than the interval".to_owned());
+    }
+    log::info!("Updating heartbeat config: {config:?}");
+    *state.connection.heartbeat.lock().unwrap() = config;
+    Ok(())
+}
+
+// Async so that connecting doesn't block the main thread
+#[tauri::command]
+pub async fn cmd_connect_peer(addr: String, state: State<'_, AppState>) -> Result<(), String> {
+    let addr = addr.trim().to_owned();
+    if addr.is_empty() {
+        return Err("No address given".to_owned());
+    }
+
+    {
+        let mut settings = state.settings.lock().unwrap();
+        if !settings.static_peers.contains(&addr) {
+            settings.static_peers.push(addr.clone());
+            settings.save();
+        }
+    }
+
+    state.connection.connect(addr).await
+}
+
+#[tauri::command]
+pub fn cmd_list_static_peers(state: State<AppState>) -> Vec<String> {
+    state.settings.lock().unwrap().static_peers.clone()
+}
+
+// Only stops us from redialing them, any current connection is left alone
+#[tauri::command]
+pub fn cmd_remove_static_peer(addr: String, state: State<AppState>) -> Vec<String> {
+    let mut settings = state.settings.lock().unwrap();
+    settings.static_peers.retain(|peer| *peer != addr.trim());
+    settings.save();
+    settings.static_peers.clone()
+}
+
+// Async so that waiting on slow peers doesn't block the main thread
+#[tauri::command]
+pub async fn cmd_send_text(msg: String, state: State<'_, AppState>) -> Result<(), String> {
+    let (name, uid) = {
+        let profile = state.profile.lock().unwrap();
+        (profile.name.clone(), profile.uid)
+    };
+
+    let msg = state.identity.lock().unwrap().sign(Message::Text(MessageData::new(
+        name,
+        uid,
+        gen_rand_id(),
+        get_curr_time(),
+        msg.as_bytes().to_vec()
+    )));
+
+    state.connection.send(vec![msg]).await
+}
+
+#[tauri::command]
+pub async fn cmd_send_img(img: String, state: State<'_, AppState>) -> Result<(), String> {
+    let (name, uid) = {
+        let profile = state.profile.lock().unwrap();
+        (profile.name.clone(), profile.uid)
+    };
+
+    let msg = state.identity.lock().unwrap().sign(Message::Image(MessageData::new(
+        name,
+        uid,
+        gen_rand_id(),
+        get_curr_time(),
+        parse_img_str(&img),
+    )));
+
+    state.connection.send(vec![msg]).await
+}
+
+// Sends msg to uid, and back to ourselves so it shows up in our chat
+async fn send_direct(uid: u32, msg: Message, state: State<'_, AppState>) -> Result<(), String> {
+    let own_uid = state.profile.lock().unwrap().uid;
+    if uid == own_uid {
+        return Err("Can't send a private message to yourself".to_owned());
+    }
+    if !state.known_users.lock().unwrap().does_user_exist(uid) {
+        return Err(format!("No user with uid {uid}"));
+    }
+
+    let reached = state.connection.send_to_reached(Some(vec![uid]), vec![msg.clone()]).await?;
+    if reached == 0 {
+        return Err(format!("uid {uid} isn't connected, or can't receive private messages"));
+    }
+    state.connection.send_to(Some(vec![own_uid]), vec![msg]).await
+}
+
+#[tauri::command]
+pub async fn cmd_send_direct_text(uid: u32, msg: String, state: State<'_, AppState>) -> Result<(), String> {
+    let (name, own_uid) = {
+        let profile = state.profile.lock().unwrap();
+        (profile.name.clone(), profile.uid)
+    };
+
+    let msg = state.identity.lock().unwrap().sign(Message::DirectText(DirectData {
+        data: MessageData::new(
+            name,
+            own_uid,
+            gen_rand_id(),
+            get_curr_time(),
+            msg.as_bytes().to_vec()
+        ),
+        to: uid,
+    }));
+
+    send_direct(uid, msg, state).await
+}
+
+#[tauri::command]
+pub async fn cmd_send_direct_img(uid: u32, img: String, state: State<'_, AppState>) -> Result<(), String> {
+    let (name, own_uid) = {
+        let profile = state.profile.lock().unwrap();
+        (profile.name.clone(), profile.uid)
+    };
+
+    let msg = state.identity.lock().unwrap().sign(Message::DirectImage(DirectData {
+        data: MessageData::new(
+            name,
+            own_uid,
+            gen_rand_id(),
+            get_curr_time(),
+            parse_img_str(&img),
+        ),
+        to: uid,
+    }));
+
+    send_direct(uid, msg, state).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PEER: u32 = 2;
+
+    fn text(uid: u32, mid: u32) -> Message {
+        Message::Text(MessageData::new("someone".to_owned(), uid, mid, 1_700_000_000, Vec::new()))
+    }
+
+    fn mids(msgs: &[Message]) -> Vec<u32> {
+        msgs.iter().filter_map(|msg| msg.chat_data()).map(|data| data.mid).collect()
+    }
+
+    #[test]
+    fn resends_everything_after_the_newest_seen() {
+        let history = [text(1, 10), text(3, 11), Message::Ack { uid: 3, mid: 11 }, text(1, 12)];
+        let missed = find_missed_msgs(&history, &[10], Some(PEER), &Groups::new());
+        assert_eq!(mids(&missed), vec![11, 12]);
+
+        // only what comes after the newest one counts
+        let missed = find_missed_msgs(&history, &[11, 10], Some(PEER), &Groups::new());
+        assert_eq!(mids(&missed), vec![12]);
+    }
+
+    #[test]
+    fn skips_what_they_have() {
+        let history = [text(1, 10), text(PEER, 11), text(1, 12), text(1, 13)];
+        let missed = find_missed_msgs(&history, &[10, 12], Some(PEER), &Groups::new());
+        assert_eq!(mids(&missed), vec![13]);
+
+        let missed = find_missed_msgs(&history, &[10], Some(PEER), &Groups::new());
+        assert_eq!(mids(&missed), vec![12, 13]);
+    }
+
+    #[test]
+    fn sends_nothing_without_a_shared_message() {
+        let history = [text(1, 10), text(1, 11)];
+        assert!(find_missed_msgs(&history, &[], Some(PEER), &Groups::new()).is_empty());
+        assert!(find_missed_msgs(&history, &[99], Some(PEER), &Groups::new()).is_empty());
+    }
+
+    #[test]
+    fn only_resends_private_messages_to_who_they_were_for() {
+        let direct = |mid, to| Message::DirectText(DirectData {
+            data: MessageData::new("someone".to_owned(), 1, mid, 1_700_000_000, Vec::new()),
+            to,
+        });
+        let history = [text(1, 10), direct(11, PEER), direct(12, 3)];
+        let missed = find_missed_msgs(&history, &[10], Some(PEER), &Groups::new());
+        assert_eq!(mids(&missed), vec![11]);
+    }
+
+    #[test]
+    fn only_resends_group_messages_to_members() {
+        let mut groups = Groups::new();
+        groups.apply(&message::GroupData {
+            data: MessageData::new("someone".to_owned(), 1, 50, 1_700_000_000, Vec::new()),
+            gid: 100,
+            group_name: "friends".to_owned(),
+            members: vec![1, PEER],
+        }, 1);
+
+        let group_text = |mid, gid| Message::GroupText(GroupMsgData {
+            data: MessageData::new("someone".to_owned(), 1, mid, 1_700_000_000, Vec::new()),
+            gid,
+        });
+        let history = [text(1, 10), group_text(11, 100), group_text(12, 200)];
+        let missed = find_missed_msgs(&history, &[10], Some(PEER), &groups);
+        assert_eq!(mids(&missed), vec![11]);
+        assert!(find_missed_msgs(&history, &[10], Some(3), &groups).is_empty());
+    }
+}
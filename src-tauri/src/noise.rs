@@ -0,0 +1,275 @@
+// Encryption of everything sent over a peer's tcp stream. Right after
+// connecting, before the Hello, both sides run a Noise XX handshake, and from
+// then on every frame is sent in AEAD encrypted records.
+//
+// Older builds know nothing about this and say Hello straight away. Whoever
+// dialed them can tell from the version in their broadcast (or by waiting a
+// moment to see if they speak first, for static peers), and whoever they
+// dialed can tell from the first frame. Either way we carry on in plaintext
+// with them, unless encryption is required.
+//
+// Handshake messages are framed like any other message, but with NOISE_MAGIC
+// where the body of a frame would start. After the handshake, each record is
+// its length as a little endian u16, followed by that many bytes of ciphertext.
+
+use std::{sync::Arc, time::Duration};
+use snow::{Builder, HandshakeState, Keypair, StatelessTransportState};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tauri::State;
+
+use crate::message::{Message, MessageLimits, DecodeError, HEADER_LEN};
+use crate::peer::PeerError;
+use crate::AppState;
+
+const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+// Can't be the start of a gzip (0x1f) or binary (codec::MAGIC) frame body
+const NOISE_MAGIC: u8 = 0xEE;
+// Builds from this version on start every connection with a handshake
+pub const NOISE_VERSION: u16 = 2;
+
+const MAX_NOISE_LEN: usize = 65535;
+const TAG_LEN: usize = 16;
+const MAX_RECORD_PAYLOAD: usize = MAX_NOISE_LEN - TAG_LEN;
+
+const HANDSHAKE_TIMEOUT: u64 = 5; // give up on a handshake after 5s
+// Older builds say Hello as soon as they accept a connection, so if nothing
+// has arrived by now, whoever we dialed is waiting for us to start a handshake
+const PROBE_TIME: u64 = 500; // in ms
+
+// How a connection starts
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Handshake {
+    Initiate, // we dialed someone who speaks Noise
+    Plain, // we dialed an older build
+    Probe, // we dialed someone we know nothing about
+    Respond, // they dialed us, so go by whatever they send first
+}
+
+impl Handshake {
+    // For dialing a peer that told us its protocol version
+    pub fn for_version(protocol_version: u16) -> Handshake {
+        if protocol_version >= NOISE_VERSION {
+            Handshake::Initiate
+        } else {
+            Handshake::Plain
+        }
+    }
+}
+
+// Our static keypair, made fresh each launch
+pub fn generate_keypair() -> Keypair {
+    Builder::new(NOISE_PARAMS.parse().unwrap())
+        .generate_keypair()
+        .expect("could not generate noise keypair")
+}
+
+// Encrypts or decrypts one direction of a connection. Each direction keeps
+// its own count of records for the nonce.
+pub struct Cipher {
+    transport: Arc<StatelessTransportState>,
+    nonce: u64,
+}
+
+impl Cipher {
+    // All of plaintext, as however many records it takes
+    pub fn seal(&mut self, plaintext: &[u8]) -> Result<Vec<u8>, snow::Error> {
+        let mut out = Vec::with_capacity(plaintext.len() + plaintext.len() / MAX_RECORD_PAYLOAD * (TAG_LEN + 2) + TAG_LEN + 2);
+        let mut record = vec![0u8; MAX_NOISE_LEN];
+        for payload in plaintext.chunks(MAX_RECORD_PAYLOAD) {
+            let len = self.transport.write_message(self.nonce, payload, &mut record)?;
+            self.nonce += 1;
+            out.extend((len as u16).to_le_bytes());
+            out.extend_from_slice(&record[..len]);
+        }
+        Ok(out)
+    }
+
+    // Reads and decrypts the next record
+    pub async fn open(&mut self, stream: &mut (impl AsyncReadExt + Unpin)) -> Result<Vec<u8>, PeerError> {
+        let mut len = [0u8; 2];
+        stream.read_exact(&mut len).await?;
+        let mut record = vec![0u8; u16::from_le_bytes(len) as usize];
+        stream.read_exact(&mut record).await?;
+
+        let mut plaintext = vec![0u8; record.len()];
+        let len = self.transport.read_message(self.nonce, &record, &mut plaintext)
+            .map_err(|e| DecodeError::Encryption(format!("could not decrypt record {}: {e}", self.nonce)))?;
+        self.nonce += 1;
+        plaintext.truncate(len);
+        Ok(plaintext)
+    }
+}
+
+// How a connection turned out once the handshake is done
+pub struct Established {
+    pub ciphers: Option<(Cipher, Cipher)>, // for reading and writing, None in plaintext
+    pub session: Option<Session>, // None in plaintext
+    pub first_msg: Option<Message>, // read while working out whether they speak Noise
+}
+
+// What an encrypted connection's handshake settled on
+pub struct Session {
+    pub handshake_hash: Vec<u8>, // unique to the connection
+    pub remote_key: Vec<u8>, // their static public key, ours if we connected to ourselves
+}
+
+impl Established {
+    fn plain(first_msg: Option<Message>, required: bool) -> Result<Established, PeerError> {
+        if required {
+            return Err(PeerError::Refused("they don't support encryption, which is required".to_owned()));
+        }
+        Ok(Established { ciphers: None, session: None, first_msg })
+    }
+}
+
+pub async fn handshake(
+    stream: &mut TcpStream,
+    handshake: Handshake,
+    key: &[u8],
+    required: bool,
+    limits: &MessageLimits,
+) -> Result<Established, PeerError> {
+    let timeout = Duration::from_secs(HANDSHAKE_TIMEOUT);
+    match tokio::time::timeout(timeout, run_handshake(stream, handshake, key, required, limits)).await {
+        Ok(result) => result,
+        Err(_) => Err(PeerError::Io(std::io::Error::new(std::io::ErrorKind::TimedOut, "handshake timed out"))),
+    }
+}
+
+async fn run_handshake(
+    stream: &mut TcpStream,
+    handshake: Handshake,
+    key: &[u8],
+    required: bool,
+    limits: &MessageLimits,
+) -> Result<Established, PeerError> {
+    match handshake {
+        Handshake::Initiate => initiate(stream, key).await,
+        Handshake::Plain => Established::plain(None, required),
+        Handshake::Probe => {
+            let mut byte = [0u8; 1];
+            match tokio::time::timeout(Duration::from_millis(PROBE_TIME), stream.peek(&mut byte)).await {
+                Ok(Ok(0)) => Err(PeerError::Closed),
+                Ok(Ok(_)) => Established::plain(None, required), // their Hello is on its way
+                Ok(Err(e)) => Err(e.into()),
+                Err(_) => initiate(stream, key).await,
+            }
+        },
+        Handshake::Respond => {
+            let frame = read_frame(stream, limits).await?;
+            if frame.get(HEADER_LEN) == Some(&NOISE_MAGIC) {
+                respond(stream, key, &frame[HEADER_LEN + 1..]).await
+            } else {
+                let first_msg = Message::from_network(&frame, limits)?;
+                Established::plain(Some(first_msg), required)
+            }
+        },
+    }
+}
+
+fn noise_err(e: snow::Error) -> PeerError {
+    PeerError::Decode(DecodeError::Encryption(format!("handshake failed: {e}")))
+}
+
+// -> e
+// <- e, ee, s, es
+// -> s, se
+async fn initiate(stream: &mut TcpStream, key: &[u8]) -> Result<Established, PeerError> {
+    let mut noise = Builder::new(NOISE_PARAMS.parse().unwrap())
+        .local_private_key(key)
+        .build_initiator()
+        .map_err(noise_err)?;
+
+    write_handshake_msg(stream, &mut noise).await?;
+    read_handshake_msg(stream, &mut noise).await?;
+    write_handshake_msg(stream, &mut noise).await?;
+    finish(noise)
+}
+
+async fn respond(stream: &mut TcpStream, key: &[u8], first: &[u8]) -> Result<Established, PeerError> {
+    let mut noise = Builder::new(NOISE_PARAMS.parse().unwrap())
+        .local_private_key(key)
+        .build_responder()
+        .map_err(noise_err)?;
+
+    let mut payload = vec![0u8; MAX_NOISE_LEN];
+    noise.read_message(first, &mut payload).map_err(noise_err)?;
+    write_handshake_msg(stream, &mut noise).await?;
+    read_handshake_msg(stream, &mut noise).await?;
+    finish(noise)
+}
+
+fn finish(noise: HandshakeState) -> Result<Established, PeerError> {
+    // XX always sends both static keys
+    let session = Session {
+        handshake_hash: noise.get_handshake_hash().to_vec(),
+        remote_key: noise.get_remote_static().unwrap_or_default().to_vec(),
+    };
+    let transport = Arc::new(noise.into_stateless_transport_mode().map_err(noise_err)?);
+    Ok(Established {
+        ciphers: Some((
+            Cipher { transport: transport.clone(), nonce: 0 },
+            Cipher { transport, nonce: 0 },
+        )),
+        session: Some(session),
+        first_msg: None,
+    })
+}
+
+async fn write_handshake_msg(stream: &mut TcpStream, noise: &mut HandshakeState) -> Result<(), PeerError> {
+    let mut msg = vec![0u8; MAX_NOISE_LEN];
+    let len = noise.write_message(&[], &mut msg).map_err(noise_err)?;
+
+    let body_len = (len + 1) as u64;
+    let frame = [&body_len.to_le_bytes()[..], &[NOISE_MAGIC], &msg[..len]].concat();
+    stream.write_all(&frame).await?;
+    Ok(())
+}
+
+async fn read_handshake_msg(stream: &mut TcpStream, noise: &mut HandshakeState) -> Result<(), PeerError> {
+    let mut header = [0u8; HEADER_LEN];
+    stream.read_exact(&mut header).await?;
+    let body_len = u64::from_le_bytes(header);
+    if body_len < 1 || body_len > (MAX_NOISE_LEN + 1) as u64 {
+        return Err(DecodeError::Encryption(format!("handshake message of {body_len} bytes")).into());
+    }
+
+    let mut body = vec![0u8; body_len as usize];
+    stream.read_exact(&mut body).await?;
+    if body[0] != NOISE_MAGIC {
+        return Err(DecodeError::Encryption("expected a handshake message".to_owned()).into());
+    }
+
+    let mut payload = vec![0u8; MAX_NOISE_LEN];
+    noise.read_message(&body[1..], &mut payload).map_err(noise_err)?;
+    Ok(())
+}
+
+// A whole frame, header included, however it turns out to be encoded
+async fn read_frame(stream: &mut TcpStream, limits: &MessageLimits) -> Result<Vec<u8>, PeerError> {
+    let mut header = [0u8; HEADER_LEN];
+    stream.read_exact(&mut header).await?;
+    let msg_len = u64::from_le_bytes(header);
+    limits.check_frame_len(msg_len)?;
+
+    let mut frame = vec![0u8; HEADER_LEN + msg_len as usize];
+    frame[..HEADER_LEN].copy_from_slice(&header);
+    stream.read_exact(&mut frame[HEADER_LEN..]).await?;
+    Ok(frame)
+}
+
+#[tauri::command]
+pub fn cmd_get_encryption_required(state: State<AppState>) -> bool {
+    state.settings.lock().unwrap().encryption_required
+}
+
+// Only checked as connections are made, so nobody we are already talking
+// to in plaintext is cut off
+#[tauri::command]
+pub fn cmd_set_encryption_required(required: bool, state: State<AppState>) {
+    log::info!("Updating encryption required to {required}");
+    let mut settings = state.settings.lock().unwrap();
+    settings.encryption_required = required;
+    settings.save();
+}
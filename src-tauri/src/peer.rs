@@ -0,0 +1,657 @@
+// Reading and writing whole messages on a peer's tcp stream. Every connection
+// gets a reader task and a writer task, which only ever talk to the network
+// task over channels. Anything that changes what actually goes over the wire
+// (e.g. encryption) belongs in PeerReader and PeerWriter.
+//
+// Before either task starts, establish runs the handshake (see noise.rs).
+
+use std::{fmt, sync::{Arc, Mutex}, time::Duration, collections::HashMap};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::sync::mpsc::{self, Receiver, Sender, UnboundedSender};
+use tokio::sync::mpsc::error::{SendError, TrySendError};
+use sha2::{Sha256, Digest};
+
+use crate::message::{Message, MessageLimits, DecodeError, WireFormat, Priority, ChunkData, HEADER_LEN, CHUNK_LEN};
+use crate::noise::{self, Cipher, Handshake, Session};
+use crate::utilities::gen_rand_id;
+
+pub type PeerId = u64;
+
+// How many messages can be waiting in each of a peer's lanes. Once one is
+// full, sending waits for the peer to catch up instead of piling up more.
+const OUTBOUND_QUEUE_LEN: usize = 16;
+// A peer that hasn't taken any of a message for this long has stopped
+// reading, and would otherwise hold up everyone sending to it forever
+const WRITE_TIMEOUT: u64 = 30;
+// Only one transfer comes from each of a peer's lanes at a time, so anything
+// more than this is a peer trying to make us buffer for it
+const MAX_PARTIAL_TRANSFERS: usize = 4;
+
+// Why a connection ended
+#[derive(Debug)]
+pub enum PeerError {
+    Closed, // EOF, they hung up without saying Goodbye
+    Io(std::io::Error),
+    Decode(DecodeError), // they sent us something we won't accept
+    Refused(String), // we won't talk to them, e.g. they can't encrypt and we require it
+}
+
+impl fmt::Display for PeerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PeerError::Closed => write!(f, "connection closed"),
+            PeerError::Io(e) => write!(f, "{e}"),
+            PeerError::Decode(e) => write!(f, "{e}"),
+            PeerError::Refused(reason) => write!(f, "refused: {reason}"),
+        }
+    }
+}
+
+impl From<DecodeError> for PeerError {
+    fn from(e: DecodeError) -> Self {
+        PeerError::Decode(e)
+    }
+}
+
+impl From<std::io::Error> for PeerError {
+    fn from(e: std::io::Error) -> Self {
+        if e.kind() == std::io::ErrorKind::UnexpectedEof {
+            PeerError::Closed
+        } else {
+            PeerError::Io(e)
+        }
+    }
+}
+
+// What the network task can ask a writer task to do
+pub enum Outgoing {
+    Msg(Message),
+    Format(WireFormat), // upgraded once hello msg received
+    Chunks(bool), // set once hello msg received, if they can put chunks back together
+    // Close the connection once everything queued before this has been
+    // written, in every lane, and then the message given (e.g. a Goodbye).
+    // The sender is dropped when we are done, so the other end can wait on it.
+    Close(Option<Box<Message>>, std::sync::mpsc::Sender<()>),
+}
+
+impl Outgoing {
+    fn priority(&self) -> Priority {
+        match self {
+            Outgoing::Msg(msg) => msg.priority(),
+            _ => Priority::Control,
+        }
+    }
+}
+
+// How far along a chunked message is, in bytes of its frame
+pub struct Progress {
+    pub transfer_id: u32,
+    pub sending: bool,
+    pub bytes: u64,
+    pub total: u64,
+}
+
+// What reader and writer tasks tell the network task
+pub enum PeerEvent {
+    // The handshake is done, with what it settled on if encrypted. Always
+    // comes before anything is received.
+    Established(PeerId, Option<Session>),
+    Received(PeerId, Message),
+    Progress(PeerId, Progress),
+    Closed(PeerId, PeerError),
+}
+
+// What came off the wire, once chunks have been put back together
+pub enum Incoming {
+    Msg(Message),
+    Progress(Progress), // another chunk of a message arrived
+}
+
+// The sending end of a peer's lanes. Messages go in the lane for their
+// priority, and everything else goes in the control lane.
+#[derive(Clone)]
+pub struct PeerSender {
+    control: Sender<Outgoing>,
+    text: Sender<Outgoing>,
+    media: Sender<Outgoing>,
+}
+
+impl PeerSender {
+    fn lane(&self, out: &Outgoing) -> &Sender<Outgoing> {
+        match out.priority() {
+            Priority::Control => &self.control,
+            Priority::Text => &self.text,
+            Priority::Media => &self.media,
+        }
+    }
+
+    pub fn try_send(&self, out: Outgoing) -> Result<(), TrySendError<Outgoing>> {
+        self.lane(&out).try_send(out)
+    }
+
+    pub async fn send(&self, out: Outgoing) -> Result<(), SendError<Outgoing>> {
+        self.lane(&out).send(out).await
+    }
+}
+
+pub struct PeerReceiver {
+    control: Receiver<Outgoing>,
+    text: Receiver<Outgoing>,
+    media: Receiver<Outgoing>,
+}
+
+pub fn channel() -> (PeerSender, PeerReceiver) {
+    let (control_tx, control) = mpsc::channel(OUTBOUND_QUEUE_LEN);
+    let (text_tx, text) = mpsc::channel(OUTBOUND_QUEUE_LEN);
+    let (media_tx, media) = mpsc::channel(OUTBOUND_QUEUE_LEN);
+    (
+        PeerSender { control: control_tx, text: text_tx, media: media_tx },
+        PeerReceiver { control, text, media },
+    )
+}
+
+// A chunked message we are in the middle of receiving
+struct PartialTransfer {
+    next_seq: u32,
+    total_len: u64,
+    frame: Vec<u8>,
+}
+
+pub struct PeerReader {
+    stream: OwnedReadHalf,
+    limits: Arc<Mutex<MessageLimits>>,
+    transfers: HashMap<u32, PartialTransfer>, // keyed by transfer id
+    finished: Option<Message>, // put back together, returned after its last Progress
+    cipher: Option<Cipher>, // None if talking plaintext
+    plaintext: Vec<u8>, // decrypted, but not read yet
+}
+
+impl PeerReader {
+    // Chunks are put back together here, so they are never returned as messages
+    pub async fn read(&mut self) -> Result<Incoming, PeerError> {
+        if let Some(msg) = self.finished.take() {
+            return Ok(Incoming::Msg(msg));
+        }
+
+        match self.read_frame().await? {
+            Message::Chunk(chunk) => Ok(Incoming::Progress(self.add_chunk(chunk)?)),
+            msg => Ok(Incoming::Msg(msg)),
+        }
+    }
+
+    async fn read_frame(&mut self) -> Result<Message, PeerError> {
+        let mut header = [0u8; HEADER_LEN];
+        self.read_exact(&mut header).await?;
+
+        let limits = self.limits.lock().unwrap().clone();
+        let msg_len = u64::from_le_bytes(header); // len of msg object
+        // Don't even try to allocate for it if it's too big
+        limits.check_frame_len(msg_len)?;
+
+        let mut frame = vec![0u8; HEADER_LEN + msg_len as usize]; // include 8 bytes from header
+        frame[..HEADER_LEN].copy_from_slice(&header);
+        self.read_exact(&mut frame[HEADER_LEN..]).await?;
+
+        Ok(Message::from_network(&frame, &limits)?)
+    }
+
+    // Records don't line up with frames, so decrypted bytes are kept until
+    // something reads them
+    async fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), PeerError> {
+        let cipher = match &mut self.cipher {
+            Some(cipher) => cipher,
+            None => {
+                self.stream.read_exact(buf).await?;
+                return Ok(());
+            },
+        };
+
+        let mut filled = 0;
+        while filled < buf.len() {
+            if self.plaintext.is_empty() {
+                self.plaintext = cipher.open(&mut self.stream).await?;
+            }
+            let len = self.plaintext.len().min(buf.len() - filled);
+            buf[filled..filled + len].copy_from_slice(&self.plaintext[..len]);
+            self.plaintext.drain(..len);
+            filled += len;
+        }
+        Ok(())
+    }
+
+    // Once the last chunk arrives, the whole message is waiting in finished
+    fn add_chunk(&mut self, chunk: ChunkData) -> Result<Progress, DecodeError> {
+        let limits = self.limits.lock().unwrap().clone();
+
+        if !self.transfers.contains_key(&chunk.transfer_id) {
+            if chunk.seq != 0 {
+                return Err(DecodeError::BadChunk(format!("transfer {} started at chunk {}", chunk.transfer_id, chunk.seq)));
+            }
+            if self.transfers.len() >= MAX_PARTIAL_TRANSFERS {
+                return Err(DecodeError::BadChunk(format!("more than {MAX_PARTIAL_TRANSFERS} transfers at once")));
+            }
+            // Same limit as if it had been sent whole, so we know up front
+            limits.check_frame_len(chunk.total_len.saturating_sub(HEADER_LEN as u64))?;
+            self.transfers.insert(chunk.transfer_id, PartialTransfer {
+                next_seq: 0,
+                total_len: chunk.total_len,
+                frame: Vec::new(),
+            });
+        }
+
+        let transfer = self.transfers.get_mut(&chunk.transfer_id).unwrap();
+        if chunk.seq != transfer.next_seq {
+            return Err(DecodeError::BadChunk(format!(
+                "expected chunk {} of transfer {}, got {}", transfer.next_seq, chunk.transfer_id, chunk.seq
+            )));
+        }
+        if chunk.total_len != transfer.total_len {
+            return Err(DecodeError::BadChunk(format!("length of transfer {} changed", chunk.transfer_id)));
+        }
+        let frame_len = (transfer.frame.len() + chunk.payload.len()) as u64;
+        if frame_len > transfer.total_len || (chunk.last && frame_len != transfer.total_len) {
+            return Err(DecodeError::BadChunk(format!(
+                "transfer {} is {frame_len} bytes, expected {}", chunk.transfer_id, transfer.total_len
+            )));
+        }
+        transfer.next_seq += 1;
+        transfer.frame.extend(chunk.payload);
+
+        let progress = Progress {
+            transfer_id: chunk.transfer_id,
+            sending: false,
+            bytes: frame_len,
+            total: transfer.total_len,
+        };
+        if !chunk.last {
+            return Ok(progress);
+        }
+
+        let transfer = self.transfers.remove(&chunk.transfer_id).unwrap();
+        if Sha256::digest(&transfer.frame)[..] != chunk.hash[..] {
+            return Err(DecodeError::BadChunk(format!("hash of transfer {} doesn't match", chunk.transfer_id)));
+        }
+        match Message::from_network(&transfer.frame, &limits)? {
+            Message::Chunk(_) => return Err(DecodeError::BadChunk("chunk inside a chunk".to_owned())),
+            msg => self.finished = Some(msg),
+        }
+        Ok(progress)
+    }
+}
+
+// A message we are sending a chunk at a time
+struct Transfer {
+    id: u32,
+    frame: Vec<u8>,
+    hash: Vec<u8>,
+    seq: u32,
+    pos: usize,
+}
+
+impl Transfer {
+    fn new(frame: Vec<u8>) -> Transfer {
+        let hash = Sha256::digest(&frame).to_vec();
+        Transfer { id: gen_rand_id(), frame, hash, seq: 0, pos: 0 }
+    }
+
+    fn next_chunk(&mut self) -> Message {
+        let end = (self.pos + CHUNK_LEN).min(self.frame.len());
+        let last = end == self.frame.len();
+        let chunk = ChunkData {
+            transfer_id: self.id,
+            seq: self.seq,
+            total_len: self.frame.len() as u64,
+            last,
+            hash: if last { self.hash.clone() } else { Vec::new() },
+            payload: self.frame[self.pos..end].to_vec(),
+        };
+        self.seq += 1;
+        self.pos = end;
+        Message::Chunk(chunk)
+    }
+
+    fn progress(&self) -> Progress {
+        Progress {
+            transfer_id: self.id,
+            sending: true,
+            bytes: self.pos as u64,
+            total: self.frame.len() as u64,
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        self.pos == self.frame.len()
+    }
+}
+
+pub struct PeerWriter {
+    stream: OwnedWriteHalf,
+    format: WireFormat,
+    chunks: bool,
+    cipher: Option<Cipher>, // None if talking plaintext
+}
+
+impl PeerWriter {
+    // Writes the whole msg, however many goes that takes
+    pub async fn write_msg(&mut self, msg: &Message) -> std::io::Result<()> {
+        let mut frame = msg.to_network(self.format);
+        if let Some(cipher) = &mut self.cipher {
+            frame = cipher.seal(&frame).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, format!("could not encrypt: {e}")))?;
+        }
+        match tokio::time::timeout(Duration::from_secs(WRITE_TIMEOUT), self.stream.write_all(&frame)).await {
+            Ok(result) => result,
+            Err(_) => Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "peer stopped reading")),
+        }
+    }
+
+    pub fn set_format(&mut self, format: WireFormat) {
+        self.format = format;
+    }
+
+    pub fn set_chunks(&mut self, chunks: bool) {
+        self.chunks = chunks;
+    }
+
+    pub async fn close(&mut self) {
+        let _ = self.stream.shutdown().await;
+    }
+
+    // Returns the transfer to send msg with, if it's too big to send whole
+    fn start_transfer(&self, msg: &Message) -> Option<Transfer> {
+        // As json, a chunk would inflate to several times its size
+        if !self.chunks || self.format != WireFormat::Binary {
+            return None;
+        }
+        let frame = msg.to_network(self.format);
+        if frame.len() <= CHUNK_LEN {
+            return None;
+        }
+        Some(Transfer::new(frame))
+    }
+}
+
+// Runs the handshake, then splits the stream. Every connection starts out
+// talking json, since we don't know what the other side understands until
+// we get their Hello. Also returns what the handshake settled on, if encrypted.
+pub async fn establish(
+    mut stream: TcpStream,
+    handshake: Handshake,
+    key: &[u8],
+    required: bool,
+    limits: Arc<Mutex<MessageLimits>>,
+) -> Result<(PeerReader, PeerWriter, Option<Session>), PeerError> {
+    let current_limits = limits.lock().unwrap().clone();
+    let established = noise::handshake(&mut stream, handshake, key, required, &current_limits).await?;
+    let (read_cipher, write_cipher) = match established.ciphers {
+        Some((read_cipher, write_cipher)) => (Some(read_cipher), Some(write_cipher)),
+        None => (None, None),
+    };
+
+    let (read_half, write_half) = stream.into_split();
+    Ok((
+        PeerReader {
+            stream: read_half,
+            limits,
+            transfers: HashMap::new(),
+            finished: established.first_msg,
+            cipher: read_cipher,
+            plaintext: Vec::new(),
+        },
+        PeerWriter { stream: write_half, format: WireFormat::Json, chunks: false, cipher: write_cipher },
+        established.session,
+    ))
+}
+
+// Reads until the connection dies. A stream we can't make sense of any more
+// can't be trusted (we don't know where the next frame starts), so any error ends it.
+pub async fn run_reader(id: PeerId, mut reader: PeerReader, events: UnboundedSender<PeerEvent>) {
+    loop {
+        let event = match reader.read().await {
+            Ok(Incoming::Msg(msg)) => PeerEvent::Received(id, msg),
+            Ok(Incoming::Progress(progress)) => PeerEvent::Progress(id, progress),
+            Err(e) => {
+                let _ = events.send(PeerEvent::Closed(id, e));
+                break;
+            },
+        };
+        if events.send(event).is_err() {
+            break;
+        }
+    }
+}
+
+// Writes until the network task hangs up, asks us to close, or the
+// connection dies. Control messages always go first, then text, then media.
+// Big media messages go a chunk at a time, checking the other lanes in between.
+pub async fn run_writer(
+    id: PeerId,
+    mut writer: PeerWriter,
+    mut lanes: PeerReceiver,
+    events: UnboundedSender<PeerEvent>,
+) {
+    let mut transfer: Option<Transfer> = None;
+
+    loop {
+        let out = tokio::select! {
+            biased;
+            out = lanes.control.recv() => out,
+            out = lanes.text.recv() => out,
+            _ = std::future::ready(()), if transfer.is_some() => {
+                let t = transfer.as_mut().unwrap();
+                if let Err(e) = writer.write_msg(&t.next_chunk()).await {
+                    let _ = events.send(PeerEvent::Closed(id, PeerError::from(e)));
+                    return;
+                }
+                let _ = events.send(PeerEvent::Progress(id, t.progress()));
+                if t.is_done() {
+                    transfer = None;
+                }
+                continue;
+            },
+            out = lanes.media.recv() => match out {
+                Some(Outgoing::Msg(msg)) => match writer.start_transfer(&msg) {
+                    Some(t) => {
+                        log::trace!("Sending {} message to peer {id} in chunks", msg.get_type_str());
+                        transfer = Some(t);
+                        continue;
+                    },
+                    None => Some(Outgoing::Msg(msg)),
+                },
+                out => out,
+            },
+        };
+
+        // All the lanes close together, once the network task is done with us
+        let out = match out {
+            Some(out) => out,
+            None => break,
+        };
+
+        match out {
+            Outgoing::Msg(msg) => {
+                if let Err(e) = writer.write_msg(&msg).await {
+                    let _ = events.send(PeerEvent::Closed(id, PeerError::from(e)));
+                    return;
+                }
+            },
+            Outgoing::Format(format) => writer.set_format(format),
+            Outgoing::Chunks(chunks) => writer.set_chunks(chunks),
+            Outgoing::Close(last, _done) => {
+                let result = match flush(id, &mut writer, &mut lanes, transfer.take(), &events).await {
+                    Ok(()) => match last {
+                        Some(msg) => writer.write_msg(&msg).await,
+                        None => Ok(()),
+                    },
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = result {
+                    let _ = events.send(PeerEvent::Closed(id, PeerError::from(e)));
+                    return;
+                }
+                break;
+            },
+        }
+    }
+    writer.close().await;
+}
+
+// Writes everything still waiting in the text and media lanes, in the order
+// they would have gone out, starting with the rest of transfer
+async fn flush(
+    id: PeerId,
+    writer: &mut PeerWriter,
+    lanes: &mut PeerReceiver,
+    mut transfer: Option<Transfer>,
+    events: &UnboundedSender<PeerEvent>,
+) -> std::io::Result<()> {
+    while let Ok(out) = lanes.text.try_recv() {
+        apply(writer, out).await?;
+    }
+    loop {
+        if let Some(mut t) = transfer.take() {
+            while !t.is_done() {
+                writer.write_msg(&t.next_chunk()).await?;
+                let _ = events.send(PeerEvent::Progress(id, t.progress()));
+            }
+        }
+        match lanes.media.try_recv() {
+            Ok(Outgoing::Msg(msg)) => match writer.start_transfer(&msg) {
+                Some(t) => transfer = Some(t),
+                None => writer.write_msg(&msg).await?,
+            },
+            Ok(out) => apply(writer, out).await?,
+            Err(_) => return Ok(()),
+        }
+    }
+}
+
+// Anything but a Close, which is left to run_writer
+async fn apply(writer: &mut PeerWriter, out: Outgoing) -> std::io::Result<()> {
+    match out {
+        Outgoing::Msg(msg) => writer.write_msg(&msg).await?,
+        Outgoing::Format(format) => writer.set_format(format),
+        Outgoing::Chunks(chunks) => writer.set_chunks(chunks),
+        Outgoing::Close(_, _) => {},
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+    use crate::message::MessageData;
+
+    // add_chunk never touches the stream, but a reader needs one
+    async fn reader() -> PeerReader {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        PeerReader {
+            stream: stream.into_split().0,
+            limits: Arc::new(Mutex::new(MessageLimits::default())),
+            transfers: HashMap::new(),
+            finished: None,
+            cipher: None,
+            plaintext: Vec::new(),
+        }
+    }
+
+    fn image() -> Message {
+        // random, so it doesn't gzip down to a single chunk
+        let payload = (0..3 * CHUNK_LEN).map(|_| rand::random::<u8>()).collect();
+        Message::Image(MessageData::new("someone".to_owned(), 7, 42, 1_700_000_000, payload))
+    }
+
+    fn chunks(msg: &Message) -> Vec<ChunkData> {
+        let mut transfer = Transfer::new(msg.to_network(WireFormat::Json));
+        let mut chunks = Vec::new();
+        while !transfer.is_done() {
+            match transfer.next_chunk() {
+                Message::Chunk(chunk) => chunks.push(chunk),
+                other => panic!("expected a Chunk, got {other:?}"),
+            }
+        }
+        chunks
+    }
+
+    #[tokio::test]
+    async fn puts_chunks_back_together() {
+        let mut reader = reader().await;
+        let msg = image();
+        let chunks = chunks(&msg);
+        assert!(chunks.len() > 1);
+
+        let mut last_bytes = 0;
+        for chunk in chunks {
+            assert!(reader.finished.is_none());
+            let progress = reader.add_chunk(chunk).unwrap();
+            assert!(progress.bytes > last_bytes);
+            last_bytes = progress.bytes;
+        }
+        assert_eq!(reader.finished.take().unwrap().get_type_str(), msg.get_type_str());
+        assert!(reader.transfers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn rejects_chunks_out_of_order() {
+        let mut reader = reader().await;
+        let mut chunks = chunks(&image());
+        assert!(matches!(reader.add_chunk(chunks.remove(1)), Err(DecodeError::BadChunk(_))));
+
+        reader.add_chunk(chunks.remove(0)).unwrap();
+        // chunk 2, where chunk 1 should be
+        assert!(matches!(reader.add_chunk(chunks.remove(0)), Err(DecodeError::BadChunk(_))));
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_hashes() {
+        let mut reader = reader().await;
+        let mut chunks = chunks(&image());
+        chunks.last_mut().unwrap().hash[0] ^= 1;
+        let last = chunks.pop().unwrap();
+        for chunk in chunks {
+            reader.add_chunk(chunk).unwrap();
+        }
+        assert!(matches!(reader.add_chunk(last), Err(DecodeError::BadChunk(_))));
+        assert!(reader.finished.is_none());
+    }
+
+    #[tokio::test]
+    async fn rejects_changed_lengths() {
+        let mut reader = reader().await;
+        let mut chunks = chunks(&image());
+        reader.add_chunk(chunks.remove(0)).unwrap();
+        chunks[0].total_len += 1;
+        assert!(matches!(reader.add_chunk(chunks.remove(0)), Err(DecodeError::BadChunk(_))));
+    }
+
+    #[tokio::test]
+    async fn rejects_transfers_that_end_early() {
+        let mut reader = reader().await;
+        let mut chunks = chunks(&image());
+        chunks[0].last = true;
+        assert!(matches!(reader.add_chunk(chunks.remove(0)), Err(DecodeError::BadChunk(_))));
+    }
+
+    #[tokio::test]
+    async fn limits_transfers_at_once() {
+        let mut reader = reader().await;
+        let msg = image();
+        let mut transfers: Vec<Vec<ChunkData>> = (0..=MAX_PARTIAL_TRANSFERS).map(|_| chunks(&msg)).collect();
+        let extra = transfers.pop().unwrap();
+
+        for chunks in transfers.iter_mut() {
+            reader.add_chunk(chunks.remove(0)).unwrap();
+        }
+        assert!(matches!(reader.add_chunk(extra[0].clone()), Err(DecodeError::BadChunk(_))));
+
+        // the ones already going can still finish, which makes room again
+        for chunk in transfers.remove(0) {
+            reader.add_chunk(chunk).unwrap();
+        }
+        assert!(reader.finished.take().is_some());
+        reader.add_chunk(extra[0].clone()).unwrap();
+    }
+}
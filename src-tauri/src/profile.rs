@@ -3,8 +3,9 @@ use ts_rs::TS;
 use tauri::State;
 
 use crate::AppState;
+use crate::identity::Identity;
 use crate::utilities::{self, gen_rand_id, get_curr_time, parse_img_str};
-use crate::message::{Message, MessageData};
+use crate::message::{Message, MessageData, HelloData};
 
 #[derive(TS, Serialize, Deserialize, Clone, Ord, PartialOrd, PartialEq, Eq)]
 #[ts(export)]
@@ -14,6 +15,9 @@ pub struct Profile {
     pub uid: u32,
     pub join_time: u64,
     pub pic: Vec<u8>,
+    // of their identity, empty for older builds. See identity.rs.
+    #[serde(default)]
+    pub public_key: Vec<u8>,
 }
 
 impl Profile {
@@ -22,18 +26,29 @@ impl Profile {
             name,
             uid: utilities::gen_rand_id(),
             join_time: utilities::get_curr_time(),
-            pic: Vec::new()
+            pic: Vec::new(),
+            public_key: Vec::new(),
         }
     }
 
-    pub fn make_hello_msg(&self) -> Message {
-        Message::Hello(MessageData::new(
+    pub fn make_hello_msg(&self, listen_port: u16, room: String, handshake_sig: Vec<u8>, room_proof: Vec<u8>) -> Message {
+        Message::Hello(Box::new(HelloData::new(MessageData::new(
             self.name.clone(), 
             self.uid, 
             gen_rand_id(), 
             get_curr_time(),
             self.pic.clone()
-        ))
+        ), listen_port, room, self.public_key.clone(), handshake_sig, room_proof)))
+    }
+
+    pub fn make_goodbye_msg(&self, identity: &Identity) -> Message {
+        identity.sign(Message::Goodbye(MessageData::new(
+            self.name.clone(),
+            self.uid,
+            gen_rand_id(),
+            get_curr_time(),
+            self.pic.clone(),
+        )))
     }
 }
 
@@ -0,0 +1,400 @@
+// Chat rooms like PictoChat's A-D. We are in at most one at a time, and only
+// keep connections with peers in the same room as us. Each room has its own
+// history, so going back to a room picks up where we left it.
+//
+// Broadcasts say which room their sender is in and how many people are in it,
+// and we listen for them even before joining a room, so the user can see who
+// is in each one before picking.
+//
+// Rooms can be locked with a passphrase, which everyone in them has to know.
+// A key is derived from the passphrase with Argon2, salted with the room.
+// Right after the Noise handshake (see noise.rs), both sides swap a
+// RoomShare made from that key and the handshake hash, which gives them a
+// secret only if their keys match, and each side's Hello carries a MAC of
+// the handshake hash made with the secret. A share is made from a fresh
+// random scalar, so it gives away nothing about the passphrase, and someone
+// without it gets one guess per connection rather than a proof to guess
+// against offline. Anyone whose Hello doesn't have the right one is sent a
+// Disconnect saying why. Locked rooms only work over encrypted connections.
+
+use std::{collections::HashMap, time::{Duration, Instant}};
+use argon2::{Algorithm, Argon2, Params, Version};
+use blake2::Blake2sMac256;
+use blake2::digest::{KeyInit, Mac};
+use curve25519_dalek::ristretto::{CompressedRistretto, RistrettoPoint};
+use curve25519_dalek::scalar::Scalar;
+use curve25519_dalek::traits::IsIdentity;
+use rand::RngCore;
+use sha2::{Digest, Sha512};
+use serde::Serialize;
+use ts_rs::TS;
+use tauri::{async_runtime, State};
+
+use crate::message::Message;
+use crate::AppState;
+
+pub const ROOMS: [&str; 4] = ["A", "B", "C", "D"];
+// Where everyone starts out, and where older builds without rooms always are
+pub const DEFAULT_ROOM: &str = "A";
+// Same as PictoChat
+const DEFAULT_CAPACITY: u32 = 16;
+// Anyone we haven't heard a broadcast from in 3s is no longer nearby
+const NEARBY_TIMEOUT: u64 = 3;
+// Keeps keys and proofs from being mistaken for anything else hashed with them
+const ROOM_KEY_CONTEXT: &[u8] = b"ectochat room passphrase";
+// Argon2id costs for room keys, slow enough that guessing passphrases is
+// expensive. 19 MiB and 2 passes, as OWASP recommends.
+const ROOM_KEY_MEMORY: u32 = 19 * 1024; // in KiB
+const ROOM_KEY_PASSES: u32 = 2;
+
+#[derive(TS, Serialize, Clone, Debug)]
+#[ts(export)]
+#[ts(export_to="../src/lib/bindings/")]
+pub struct RoomInfo {
+    pub room: String,
+    pub joined: bool,
+    // uids of everyone in it we are connected to, including us. Only known
+    // for the room we are in.
+    pub members: Vec<u32>,
+    pub capacity: u32,
+    pub locked: bool, // if we have set a passphrase for it
+}
+
+// How many people are in a room, as far as we can tell from their broadcasts
+#[derive(TS, Serialize, Clone, PartialEq, Debug)]
+#[ts(export)]
+#[ts(export_to="../src/lib/bindings/")]
+pub struct NearbyRoom {
+    pub room: String,
+    pub members: u32,
+    pub locked: bool, // if anyone in it says it is
+}
+
+// The last broadcast we heard from someone
+struct Heard {
+    room: String,
+    members: u32,
+    locked: bool,
+    at: Instant,
+}
+
+pub struct Rooms {
+    current: Option<String>, // None once we leave a room, until we join another
+    members: Vec<u32>, // kept up to date by the network task
+    capacity: u32, // most people we let into our room, including us
+    histories: HashMap<String, Vec<Message>>, // of the rooms we aren't in
+    nearby: HashMap<u32, Heard>, // by uid, not including us
+    keys: HashMap<String, [u8; 32]>, // of the rooms we have a passphrase for, see room_key
+}
+
+impl Rooms {
+    pub fn new() -> Rooms {
+        Rooms {
+            current: Some(DEFAULT_ROOM.to_owned()),
+            members: Vec::new(),
+            capacity: DEFAULT_CAPACITY,
+            histories: HashMap::new(),
+            nearby: HashMap::new(),
+            keys: HashMap::new(),
+        }
+    }
+
+    pub fn current(&self) -> Option<&str> {
+        self.current.as_deref()
+    }
+
+    pub fn capacity(&self) -> u32 {
+        self.capacity
+    }
+
+    pub fn set_members(&mut self, members: Vec<u32>) {
+        self.members = members;
+    }
+
+    // Key for the room we are in, if it's locked
+    pub fn current_key(&self) -> Option<&[u8; 32]> {
+        self.keys.get(self.current()?)
+    }
+
+    pub fn list(&self) -> Vec<RoomInfo> {
+        ROOMS.iter()
+            .map(|room| {
+                let joined = self.current() == Some(*room);
+                RoomInfo {
+                    room: room.to_string(),
+                    joined,
+                    members: if joined { self.members.clone() } else { Vec::new() },
+                    capacity: self.capacity,
+                    locked: self.keys.contains_key(*room),
+                }
+            })
+            .collect()
+    }
+
+    // Not everyone in a room may be in range of our broadcasts, so we go by
+    // whichever is more: how many we heard from there, or how many the
+    // fullest of them says are in it
+    pub fn nearby(&self) -> Vec<NearbyRoom> {
+        ROOMS.iter()
+            .map(|room| {
+                let heard: Vec<&Heard> = self.nearby.values().filter(|heard| heard.room == *room).collect();
+                let reported = heard.iter().map(|heard| heard.members).max().unwrap_or(0);
+                NearbyRoom {
+                    room: room.to_string(),
+                    members: reported.max(heard.len() as u32),
+                    locked: heard.iter().any(|heard| heard.locked),
+                }
+            })
+            .collect()
+    }
+
+    // Records a broadcast from uid. Returns whether nearby changed.
+    pub fn heard(&mut self, uid: u32, room: &str, members: u32, locked: bool) -> bool {
+        let before = self.nearby();
+        self.nearby.insert(uid, Heard { room: room.to_owned(), members, locked, at: Instant::now() });
+        self.nearby() != before
+    }
+
+    // Forgets everyone who has gone quiet. Returns whether nearby changed.
+    pub fn expire_nearby(&mut self) -> bool {
+        let timeout = Duration::from_secs(NEARBY_TIMEOUT);
+        let before = self.nearby();
+        self.nearby.retain(|_, heard| heard.at.elapsed() < timeout);
+        self.nearby() != before
+    }
+
+    // Moves us into room (or out of every room if None). The history of the
+    // room we were in is put away, and msg_history becomes the history of
+    // the one we are going to.
+    pub fn switch(&mut self, room: Option<String>, msg_history: &mut Vec<Message>) {
+        log::info!("Moving from room {:?} to {room:?}", self.current);
+
+        let old_history = std::mem::take(msg_history);
+        if let Some(old_room) = self.current.take() {
+            self.histories.insert(old_room, old_history);
+        }
+        if let Some(room) = &room {
+            *msg_history = self.histories.remove(room).unwrap_or_default();
+        }
+
+        self.current = room;
+        self.members.clear();
+    }
+}
+
+// Salted with the room, so each room's passphrase has to be guessed separately
+fn room_key(room: &str, passphrase: &str) -> Result<[u8; 32], String> {
+    let params = Params::new(ROOM_KEY_MEMORY, ROOM_KEY_PASSES, 1, Some(32)).map_err(|e| e.to_string())?;
+    let salt = [ROOM_KEY_CONTEXT, room.as_bytes()].concat();
+    let mut key = [0u8; 32];
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(passphrase.as_bytes(), &salt, &mut key)
+        .map_err(|e| e.to_string())?;
+    Ok(key)
+}
+
+// Our side of the passphrase exchange on one connection, which is CPace
+// over ristretto255. The point shares are made from is derived from the room
+// key and the handshake hash, so nobody knows its discrete log and it is
+// different on every connection.
+pub struct RoomExchange {
+    scalar: Scalar,
+    share: [u8; 32],
+}
+
+impl RoomExchange {
+    pub fn new(key: &[u8; 32], handshake_hash: &[u8]) -> RoomExchange {
+        let generator = RistrettoPoint::from_uniform_bytes(&hash_parts(&[ROOM_KEY_CONTEXT, key, handshake_hash]));
+        let mut wide = [0u8; 64];
+        rand::thread_rng().fill_bytes(&mut wide);
+        let scalar = Scalar::from_bytes_mod_order_wide(&wide);
+        let share = (generator * scalar).compress().to_bytes();
+        RoomExchange { scalar, share }
+    }
+
+    // What we send them
+    pub fn share(&self) -> Vec<u8> {
+        self.share.to_vec()
+    }
+
+    // The secret we share with them if they used the same key. Both shares
+    // go in, in the order of who dialed, so it's bound to this exchange.
+    pub fn finish(&self, their_share: &[u8], handshake_hash: &[u8], dialed: bool) -> Result<[u8; 32], String> {
+        let point = CompressedRistretto::from_slice(their_share).ok()
+            .and_then(|point| point.decompress())
+            .filter(|point| !point.is_identity())
+            .ok_or_else(|| "Invalid room share".to_owned())?;
+        let shared = (point * self.scalar).compress();
+
+        let (dialer_share, responder_share) = if dialed {
+            (&self.share[..], their_share)
+        } else {
+            (their_share, &self.share[..])
+        };
+        let hash = hash_parts(&[ROOM_KEY_CONTEXT, handshake_hash, shared.as_bytes(), dialer_share, responder_share]);
+        let mut secret = [0u8; 32];
+        secret.copy_from_slice(&hash[..32]);
+        Ok(secret)
+    }
+}
+
+// Each part is length prefixed, so they can't run into each other
+fn hash_parts(parts: &[&[u8]]) -> [u8; 64] {
+    let mut hasher = Sha512::new();
+    for part in parts {
+        hasher.update((part.len() as u32).to_le_bytes());
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+// Proves whoever sent it ended up with secret from the exchange on the
+// connection with handshake_hash. Which side of the connection it's from
+// goes in too, or else either side could just send back what the other sent.
+pub fn room_proof(secret: &[u8; 32], handshake_hash: &[u8], dialed: bool) -> Vec<u8> {
+    room_mac(secret, handshake_hash, dialed).finalize().into_bytes().to_vec()
+}
+
+pub fn check_room_proof(secret: &[u8; 32], handshake_hash: &[u8], dialed: bool, proof: &[u8]) -> bool {
+    room_mac(secret, handshake_hash, dialed).verify_slice(proof).is_ok()
+}
+
+fn room_mac(secret: &[u8; 32], handshake_hash: &[u8], dialed: bool) -> Blake2sMac256 {
+    let mut mac = <Blake2sMac256 as KeyInit>::new_from_slice(secret).unwrap(); // any key up to 32 bytes is fine
+    mac.update(ROOM_KEY_CONTEXT);
+    mac.update(handshake_hash);
+    mac.update(&[dialed as u8]);
+    mac
+}
+
+#[tauri::command]
+pub fn cmd_list_rooms(state: State<AppState>) -> Vec<RoomInfo> {
+    state.rooms.lock().unwrap().list()
+}
+
+#[tauri::command]
+pub fn cmd_list_nearby_rooms(state: State<AppState>) -> Vec<NearbyRoom> {
+    state.rooms.lock().unwrap().nearby()
+}
+
+// Says goodbye to everyone in the room we are in, and returns the history of
+// the one we are joining
+#[tauri::command]
+pub async fn cmd_join_room(room: String, state: State<'_, AppState>) -> Result<Vec<Message>, String> {
+    if !ROOMS.contains(&room.as_str()) {
+        return Err(format!("There is no room {room}"));
+    }
+    if state.rooms.lock().unwrap().current() == Some(room.as_str()) {
+        return Ok(state.msg_history.lock().unwrap().clone());
+    }
+
+    let goodbye = state.profile.lock().unwrap().make_goodbye_msg(&state.identity.lock().unwrap());
+    state.connection.change_room(Some(room), goodbye).await
+}
+
+#[tauri::command]
+pub async fn cmd_leave_room(state: State<'_, AppState>) -> Result<(), String> {
+    if state.rooms.lock().unwrap().current().is_none() {
+        return Err("Not in a room".to_owned());
+    }
+
+    let goodbye = state.profile.lock().unwrap().make_goodbye_msg(&state.identity.lock().unwrap());
+    state.connection.change_room(None, goodbye).await?;
+    Ok(())
+}
+
+// Locks room, or unlocks it if passphrase is empty. Only checked as people
+// join, so nobody already in the room is removed.
+#[tauri::command]
+pub async fn cmd_set_room_passphrase(room: String, passphrase: String, state: State<'_, AppState>) -> Result<(), String> {
+    if !ROOMS.contains(&room.as_str()) {
+        return Err(format!("There is no room {room}"));
+    }
+
+    // Slow on purpose, so kept off the async threads
+    let key = if passphrase.is_empty() {
+        None
+    } else {
+        let key_room = room.clone();
+        let key = async_runtime::spawn_blocking(move || room_key(&key_room, &passphrase))
+            .await
+            .map_err(|e| e.to_string())??;
+        Some(key)
+    };
+
+    let mut rooms = state.rooms.lock().unwrap();
+    match key {
+        Some(key) => {
+            log::info!("Locking room {room}");
+            rooms.keys.insert(room.clone(), key);
+        },
+        None => {
+            log::info!("Unlocking room {room}");
+            rooms.keys.remove(&room);
+        },
+    }
+
+    // Our mDNS advertisement says whether it's locked, so has to be redone
+    if rooms.current() == Some(room.as_str()) {
+        state.connection.mdns.stop();
+    }
+    Ok(())
+}
+
+// Only checked as people join, so nobody already in the room is removed
+#[tauri::command]
+pub fn cmd_set_room_capacity(capacity: u32, state: State<AppState>) -> Result<(), String> {
+    if capacity < 2 {
+        return Err("Rooms need to fit at least 2 people".to_owned());
+    }
+    log::info!("Updating room capacity to {capacity}");
+    state.rooms.lock().unwrap().capacity = capacity;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HANDSHAKE_HASH: [u8; 32] = [3; 32];
+
+    // The secrets the dialer and whoever they dialed end up with
+    fn exchange(dialer_key: &[u8; 32], responder_key: &[u8; 32]) -> ([u8; 32], [u8; 32]) {
+        let dialer = RoomExchange::new(dialer_key, &HANDSHAKE_HASH);
+        let responder = RoomExchange::new(responder_key, &HANDSHAKE_HASH);
+        (
+            dialer.finish(&responder.share(), &HANDSHAKE_HASH, true).unwrap(),
+            responder.finish(&dialer.share(), &HANDSHAKE_HASH, false).unwrap(),
+        )
+    }
+
+    #[test]
+    fn same_key_same_secret() {
+        let (dialer, responder) = exchange(&[1; 32], &[1; 32]);
+        assert_eq!(dialer, responder);
+    }
+
+    #[test]
+    fn different_keys_different_secrets() {
+        let (dialer, responder) = exchange(&[1; 32], &[2; 32]);
+        assert_ne!(dialer, responder);
+    }
+
+    #[test]
+    fn rejects_bad_shares() {
+        let exchange = RoomExchange::new(&[1; 32], &HANDSHAKE_HASH);
+        assert!(exchange.finish(&[0; 32], &HANDSHAKE_HASH, true).is_err()); // the identity
+        assert!(exchange.finish(&[1; 31], &HANDSHAKE_HASH, true).is_err());
+    }
+
+    #[test]
+    fn proofs_are_checked_from_the_other_side() {
+        let secret = [5; 32];
+        let proof = room_proof(&secret, &HANDSHAKE_HASH, true);
+        assert!(check_room_proof(&secret, &HANDSHAKE_HASH, true, &proof));
+        // so the dialed side can't just send back the dialer's
+        assert!(!check_room_proof(&secret, &HANDSHAKE_HASH, false, &proof));
+        assert!(!check_room_proof(&[6; 32], &HANDSHAKE_HASH, true, &proof));
+        assert!(!check_room_proof(&secret, &[4; 32], true, &proof));
+    }
+}
@@ -0,0 +1,87 @@
+use std::{fs, path::PathBuf, collections::HashMap};
+use serde::{Serialize, Deserialize};
+
+use crate::trust::KnownKey;
+
+const SETTINGS_FILE: &str = "settings.json";
+// Where a settings file we couldn't parse is moved, so saving doesn't lose it
+const CORRUPT_SETTINGS_FILE: &str = "settings.json.corrupt";
+
+// Everything we remember between launches. Stored as json in the app
+// config directory. Missing fields fall back to their defaults, so older
+// settings files keep working as fields are added.
+#[derive(Serialize, Deserialize, Default, Clone)]
+#[serde(default)]
+pub struct Settings {
+    // Peers to always try to connect to, as "host:port" or "host"
+    pub static_peers: Vec<String>,
+    // Refuse connections from anyone who can't encrypt them
+    pub encryption_required: bool,
+    // Every peer identity we have seen, by fingerprint, see trust.rs
+    pub known_keys: HashMap<String, KnownKey>,
+
+    #[serde(skip)]
+    path: Option<PathBuf>,
+}
+
+impl Settings {
+    pub fn load(app: &tauri::AppHandle) -> Settings {
+        let path = match app.path_resolver().app_config_dir() {
+            Some(dir) => dir.join(SETTINGS_FILE),
+            None => {
+                log::error!("Could not find app config dir, settings will not be saved");
+                return Settings::default();
+            },
+        };
+
+        // Until whatever is there is out of the way, saving would overwrite
+        // it with the defaults, e.g. forgetting every key we know
+        let mut settings = match fs::read_to_string(&path) {
+            Ok(contents) => match serde_json::from_str(&contents) {
+                Ok(settings) => settings,
+                Err(e) => {
+                    log::error!("Error parsing {}, using default settings: {e}", path.display());
+                    let corrupt_path = path.with_file_name(CORRUPT_SETTINGS_FILE);
+                    if let Err(e) = fs::rename(&path, &corrupt_path) {
+                        log::error!("Error moving {} aside, settings will not be saved: {e}", path.display());
+                        return Settings::default();
+                    }
+                    log::info!("Moved unparseable settings to {}", corrupt_path.display());
+                    Settings::default()
+                },
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Settings::default(),
+            Err(e) => {
+                log::error!("Error reading {}, settings will not be saved: {e}", path.display());
+                return Settings::default();
+            },
+        };
+
+        log::info!("Loaded settings from {}", path.display());
+        settings.path = Some(path);
+        settings
+    }
+
+    pub fn save(&self) {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return,
+        };
+
+        if let Some(dir) = path.parent() {
+            if let Err(e) = fs::create_dir_all(dir) {
+                log::error!("Error creating {}: {e}", dir.display());
+                return;
+            }
+        }
+
+        match serde_json::to_string_pretty(self) {
+            Ok(contents) => {
+                if let Err(e) = fs::write(path, contents) {
+                    log::error!("Error writing {}: {e}", path.display());
+                }
+            },
+            Err(e) => log::error!("Error serializing settings: {e}"),
+        }
+    }
+}
@@ -0,0 +1,174 @@
+// Trust on first use for the identities in peers' Hellos (see identity.rs).
+// The first time we see a key we remember its fingerprint, along with the
+// name it came with, in our settings. Only keys that were proven in the
+// Hello are ever recorded, so a key's record (and whether it's verified)
+// only ever applies to whoever holds it. Users can compare fingerprints some
+// other way (e.g. in person) and mark a key as verified.
+//
+// uids are picked again every launch, so it's names that carry over between
+// launches. A key we have never seen under a name we have seen with another
+// key is warned about, though it's let in since names prove nothing and it
+// may just be someone else with the same name. A uid we already know turning
+// up with a different key is warned about too, and is never let in.
+
+use std::fmt::Write;
+use serde::{Serialize, Deserialize};
+use sha2::{Sha256, Digest};
+use ts_rs::TS;
+use tauri::State;
+
+use crate::message::HelloData;
+use crate::profile::Profile;
+use crate::utilities::get_curr_time;
+use crate::AppState;
+
+// Bytes of the key's SHA-256 we show
+const FINGERPRINT_LEN: usize = 16;
+
+#[derive(TS, Serialize, Deserialize, Clone, Debug)]
+#[ts(export)]
+#[ts(export_to="../src/lib/bindings/")]
+pub struct KnownKey {
+    pub fingerprint: String,
+    pub name: String, // the last one we saw it with
+    pub first_seen: u64,
+    pub verified: bool, // by the user
+}
+
+// Sent to the frontend when a key isn't the one we know someone by
+#[derive(TS, Serialize, Clone, Debug)]
+#[ts(export)]
+#[ts(export_to="../src/lib/bindings/")]
+pub struct KeyChanged {
+    pub uid: u32,
+    pub name: String,
+    pub old_fingerprint: String,
+    pub new_fingerprint: String, // empty if they didn't send a key
+    pub old_verified: bool, // if so, this is much more suspicious
+    // If they were turned away for using a uid we know, rather than let in
+    // with a name we know
+    pub refused: bool,
+}
+
+fn emit_key_changed(changed: KeyChanged, window: &tauri::Window) {
+    log::warn!(
+        "{} (uid={}) has a different key than before: {:?} was {}",
+        changed.name, changed.uid, changed.new_fingerprint, changed.old_fingerprint
+    );
+    if let Err(e) = window.emit("evt_key_changed", changed) {
+        log::error!("evt_key_changed err {e:#?}");
+    }
+}
+
+// Groups of 4 hex digits, e.g. "3f2a 9c01 ..."
+pub fn fingerprint(public_key: &[u8]) -> String {
+    let hash = Sha256::digest(public_key);
+    let mut out = String::new();
+    for (i, pair) in hash[..FINGERPRINT_LEN].chunks(2).enumerate() {
+        if i > 0 {
+            out.push(' ');
+        }
+        for byte in pair {
+            let _ = write!(out, "{byte:02x}");
+        }
+    }
+    out
+}
+
+// Records the key from someone's Hello, before they are added to known
+// users, and warns the frontend if it's new to us but their name isn't.
+// Must only be called with a key they proved was theirs, see
+// identity::check_hello. Returns what we know about the key, which for a
+// new key is never verified, whatever the name's old key was.
+pub fn check_hello(profile: &Profile, state: &State<AppState>, window: &tauri::Window) -> KnownKey {
+    let new_fingerprint = fingerprint(&profile.public_key);
+
+    let mut settings = state.settings.lock().unwrap();
+    if !settings.known_keys.contains_key(&new_fingerprint) {
+        // If several keys had the name, warn about the one they trusted most
+        let old_key = settings.known_keys.values()
+            .filter(|old| old.name == profile.name)
+            .max_by_key(|old| (old.verified, old.first_seen));
+        if let Some(old_key) = old_key {
+            emit_key_changed(KeyChanged {
+                uid: profile.uid,
+                name: profile.name.clone(),
+                old_fingerprint: old_key.fingerprint.clone(),
+                new_fingerprint: new_fingerprint.clone(),
+                old_verified: old_key.verified,
+                refused: false,
+            }, window);
+        }
+    }
+
+    let key = settings.known_keys.entry(new_fingerprint.clone())
+        .or_insert_with(|| {
+            log::info!("First time seeing key {new_fingerprint}, from {}", profile.name);
+            KnownKey {
+                fingerprint: new_fingerprint,
+                name: profile.name.clone(),
+                first_seen: get_curr_time(),
+                verified: false,
+            }
+        });
+    key.name = profile.name.clone();
+    let key = key.clone();
+    settings.save();
+    key
+}
+
+// Warns the frontend about a Hello that was turned away for using the uid of
+// someone we know without their key, see identity::check_hello
+pub fn check_refused_hello(hello: &HelloData, state: &State<AppState>, window: &tauri::Window) {
+    let uid = hello.data.uid;
+    let old_key = {
+        let known_users = state.known_users.lock().unwrap();
+        match known_users.public_key(uid) {
+            Some(public_key) if !public_key.is_empty() => {
+                known_users.key(uid).cloned().unwrap_or_else(|| KnownKey {
+                    fingerprint: fingerprint(public_key),
+                    name: hello.data.name.clone(),
+                    first_seen: 0,
+                    verified: false,
+                })
+            },
+            _ => return,
+        }
+    };
+
+    let new_fingerprint = if hello.public_key.is_empty() { String::new() } else { fingerprint(&hello.public_key) };
+    if new_fingerprint == old_key.fingerprint {
+        return; // their key, but they couldn't prove it
+    }
+    emit_key_changed(KeyChanged {
+        uid,
+        name: hello.data.name.clone(),
+        old_fingerprint: old_key.fingerprint,
+        new_fingerprint,
+        old_verified: old_key.verified,
+        refused: true,
+    }, window);
+}
+
+// Marks the key uid is using as verified, or not
+#[tauri::command]
+pub fn cmd_set_user_verified(uid: u32, verified: bool, state: State<AppState>, window: tauri::Window) -> Result<KnownKey, String> {
+    // Settings are always locked before known users, so let go of it first
+    let fingerprint = state.known_users.lock().unwrap().key(uid)
+        .map(|key| key.fingerprint.clone())
+        .ok_or_else(|| format!("No key known for uid {uid}"))?;
+
+    let key = {
+        let mut settings = state.settings.lock().unwrap();
+        let key = settings.known_keys.get_mut(&fingerprint)
+            .ok_or_else(|| format!("No key known for uid {uid}"))?;
+        key.verified = verified;
+        let key = key.clone();
+        settings.save();
+        key
+    };
+
+    log::info!("Marking key {fingerprint} of uid={uid} as {}", if verified { "verified" } else { "not verified" });
+    state.known_users.lock().unwrap().set_key(uid, key.clone(), &window);
+    Ok(key)
+}
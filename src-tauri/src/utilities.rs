@@ -5,6 +5,7 @@ use ts_rs::TS;
 
 use crate::{profile::Profile, AppState};
 use crate::message::Message;
+use crate::trust::KnownKey;
 
 pub fn gen_rand_id() -> u32 {
     rand::random()
@@ -29,26 +30,48 @@ pub fn send_msg_to_frontend(msg: &Message, window: &tauri::Window) {
 #[ts(export_to="../src/lib/bindings/")]
 pub struct KnownUsers {
     uid_to_profile: HashMap<u32, Profile>,
+    // What we know about the key each of them is using, see trust.rs. Older
+    // builds don't have one.
+    uid_to_key: HashMap<u32, KnownKey>,
 }
 
 impl KnownUsers {
     pub fn new() -> Self {
         KnownUsers {
-            uid_to_profile: HashMap::new()
+            uid_to_profile: HashMap::new(),
+            uid_to_key: HashMap::new(),
         }
     }
 
-    pub fn add_user(&mut self, prof: Profile, window: &tauri::Window) {
+    pub fn add_user(&mut self, prof: Profile, key: Option<KnownKey>, window: &tauri::Window) {
+        match key {
+            Some(key) => self.uid_to_key.insert(prof.uid, key),
+            None => self.uid_to_key.remove(&prof.uid),
+        };
         self.uid_to_profile.insert(prof.uid, prof);
         let _ = window.emit("evt_known_users_changed", self.clone());
     }
 
+    pub fn key(&self, uid: u32) -> Option<&KnownKey> {
+        self.uid_to_key.get(&uid)
+    }
+
+    pub fn set_key(&mut self, uid: u32, key: KnownKey, window: &tauri::Window) {
+        self.uid_to_key.insert(uid, key);
+        let _ = window.emit("evt_known_users_changed", self.clone());
+    }
+
     pub fn does_user_exist(&self, uid: u32) -> bool {
         self.uid_to_profile.contains_key(&uid)
     }
 
+    pub fn public_key(&self, uid: u32) -> Option<&[u8]> {
+        self.uid_to_profile.get(&uid).map(|prof| prof.public_key.as_slice())
+    }
+
     pub fn remove_user(&mut self, uid: u32, window: &tauri::Window) -> Option<Profile> {
         let old_profile = self.uid_to_profile.remove(&uid);
+        self.uid_to_key.remove(&uid);
         let _ = window.emit("evt_known_users_changed", self.clone());
         old_profile
     }
@@ -0,0 +1,4 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { MessageData } from "./MessageData";
+
+export type AudioData = { duration_ms: number, sample_rate: number, } & MessageData;
\ No newline at end of file
@@ -0,0 +1,3 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+
+export interface BroadcastData { uid: number, port: number, protocol_version: number, room: string, members: number, locked: boolean, }
\ No newline at end of file
@@ -0,0 +1,3 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+
+export interface ChunkData { transfer_id: number, seq: number, total_len: bigint, last: boolean, hash: Array<number>, payload: Array<number>, }
\ No newline at end of file
@@ -0,0 +1,3 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+
+export interface DiscoveryConfig { broadcast: boolean, multicast_v4: boolean, multicast_v6: boolean, mdns: boolean, }
\ No newline at end of file
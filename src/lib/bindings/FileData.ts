@@ -0,0 +1,4 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { MessageData } from "./MessageData";
+
+export type FileData = { file_name: string, size: bigint, mime: string, hash: Array<number>, } & MessageData;
\ No newline at end of file
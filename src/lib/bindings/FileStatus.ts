@@ -0,0 +1,4 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { FileState } from "./FileState";
+
+export interface FileStatus { mid: number, uid: number, state: FileState, received: bigint, size: bigint, error: string | null, }
\ No newline at end of file
@@ -0,0 +1,4 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { MessageData } from "./MessageData";
+
+export type HelloData = { protocol_version: number, capabilities: Array<string>, listen_port: number, room: string, public_key: Array<number>, handshake_sig: Array<number>, room_proof: Array<number>, } & MessageData;
\ No newline at end of file
@@ -0,0 +1,3 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+
+export interface KeyChanged { uid: number, name: string, old_fingerprint: string, new_fingerprint: string, old_verified: boolean, refused: boolean, }
\ No newline at end of file
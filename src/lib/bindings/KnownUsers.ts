@@ -1,4 +1,5 @@
 // This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { KnownKey } from "./KnownKey";
 import type { Profile } from "./Profile";
 
-export interface KnownUsers { uid_to_profile: Record<number, Profile>, }
\ No newline at end of file
+export interface KnownUsers { uid_to_profile: Record<number, Profile>, uid_to_key: Record<number, KnownKey>, }
\ No newline at end of file
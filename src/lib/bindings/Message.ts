@@ -1,4 +1,12 @@
 // This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { AudioData } from "./AudioData";
+import type { BroadcastData } from "./BroadcastData";
+import type { ChunkData } from "./ChunkData";
+import type { DirectData } from "./DirectData";
+import type { FileData } from "./FileData";
+import type { GroupData } from "./GroupData";
+import type { GroupMsgData } from "./GroupMsgData";
+import type { HelloData } from "./HelloData";
 import type { MessageData } from "./MessageData";
 
-export type Message = { "Broadcast": number } | { "Hello": MessageData } | { "Goodbye": MessageData } | { "Dropped": MessageData } | { "Text": MessageData } | { "Image": MessageData } | { "Ack": { uid: number, mid: number, } };
\ No newline at end of file
+export type Message = { "Broadcast": BroadcastData } | { "Hello": HelloData } | { "Goodbye": MessageData } | { "Dropped": MessageData } | { "Text": MessageData } | { "Image": MessageData } | { "Ack": { uid: number, mid: number, } } | { "Ping": number } | { "Pong": number } | { "Sync": { seen: Array<number>, } } | { "Chunk": ChunkData } | { "File": FileData } | { "FileRequest": { mid: number, offset: bigint, } } | { "FilePart": { mid: number, offset: bigint, data: Array<number>, } } | { "Audio": AudioData } | { "DirectText": DirectData } | { "DirectImage": DirectData } | { "GroupCreate": GroupData } | { "GroupUpdate": GroupData } | { "GroupText": GroupMsgData } | { "GroupImage": GroupMsgData } | { "Disconnect": { reason: string, } } | { "RoomShare": Array<number> };
\ No newline at end of file
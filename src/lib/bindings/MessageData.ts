@@ -1,3 +1,3 @@
 // This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
 
-export interface MessageData { name: string, uid: number, mid: number, timestamp: bigint, payload: Array<number>, }
\ No newline at end of file
+export interface MessageData { name: string, uid: number, mid: number, timestamp: bigint, payload: Array<number>, signature: Array<number>, verified: boolean, }
\ No newline at end of file
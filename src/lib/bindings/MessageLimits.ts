@@ -0,0 +1,4 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { SizeLimit } from "./SizeLimit";
+
+export interface MessageLimits { broadcast: SizeLimit, hello: SizeLimit, goodbye: SizeLimit, dropped: SizeLimit, text: SizeLimit, image: SizeLimit, ack: SizeLimit, heartbeat: SizeLimit, sync: SizeLimit, chunk: SizeLimit, file: SizeLimit, file_request: SizeLimit, file_part: SizeLimit, audio: SizeLimit, group: SizeLimit, disconnect: SizeLimit, room_share: SizeLimit, }
\ No newline at end of file
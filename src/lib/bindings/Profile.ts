@@ -1,3 +1,3 @@
 // This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
 
-export interface Profile { name: string, uid: number, join_time: bigint, pic: Array<number>, }
\ No newline at end of file
+export interface Profile { name: string, uid: number, join_time: bigint, pic: Array<number>, public_key: Array<number>, }
\ No newline at end of file
@@ -0,0 +1,3 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+
+export interface RoomInfo { room: string, joined: boolean, members: Array<number>, capacity: number, locked: boolean, }
\ No newline at end of file
@@ -0,0 +1,3 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+
+export interface TransferProgress { transfer_id: number, uid: number | null, sending: boolean, bytes: bigint, total: bigint, }
\ No newline at end of file
@@ -8,6 +8,12 @@ export const msg_history: Writable<Array<Message>> = writable([]);
 export const profile: Writable<Profile | null> = writable(null);
 export const known_users: Writable<KnownUsers | null> = writable(null);
 
+// Things that happened to a connection which the user should know about,
+// kept until they are dismissed. Warnings are for things that may be someone
+// pretending to be someone else, and stand out more.
+export type Notice = { id: number, who: string, text: string, warning: boolean };
+export const notices: Writable<Array<Notice>> = writable([]);
+
 // This will be set to true when a modal is closed via the esc key or clicking
 // outside the modal itself. Then, the component that is handling the modal
 // can know when the modal is closed and do appropriate styling/variable